@@ -0,0 +1,487 @@
+//! [TcpStreamBuilder] gathering everything needed to create [RetryingTcpStream].
+
+use std::error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{Error, ErrorKind};
+use tokio::net::TcpStream;
+
+use crate::classify::{EofPolicy, ErrorClassifier};
+use crate::handshake::Handshake;
+use crate::policy::ReconnectPolicy;
+use crate::queue::QueuePolicy;
+use crate::replay::ReplayPolicy;
+use crate::resolve::{Resolver, TokioResolver};
+use crate::spool::Spool;
+use crate::stream::RetryingStream;
+use crate::targets::{Strategy, TargetSet};
+use crate::tcp::{RetryingTcpStream, TcpConnector, TcpEvent, TcpStreamSettings, TcpTarget};
+
+type Callback = Box<dyn FnMut(&TcpEvent) + Send>;
+
+/// Builder of [RetryingTcpStream], created with [RetryingTcpStream::builder].
+///
+/// At least one target is required, everything else has the same default as when stream is
+/// created with [connect_with_settings](RetryingTcpStream::connect_with_settings). Settings are
+/// validated by [build](TcpStreamBuilder::build).
+pub struct TcpStreamBuilder {
+    name: Option<String>,
+    targets: Vec<TcpTarget>,
+    // first host that failed to parse
+    invalid_host: Option<String>,
+    strategy: Strategy,
+    failback_after: Option<Duration>,
+    settings: TcpStreamSettings,
+    resolver: Arc<dyn Resolver>,
+    policy: Option<Box<dyn ReconnectPolicy>>,
+    stable_period: Option<Duration>,
+    handshake: Option<Box<dyn Handshake<TcpStream>>>,
+    classifier: Option<Box<dyn ErrorClassifier>>,
+    eof_policy: EofPolicy,
+    max_attempts: Option<u32>,
+    give_up_after: Option<Duration>,
+    replay: Option<ReplayPolicy>,
+    queue: Option<QueuePolicy>,
+    spool: Option<Spool>,
+    callbacks: Vec<Callback>,
+}
+
+impl TcpStreamBuilder {
+    pub(crate) fn new() -> Self {
+        Self {
+            name: None,
+            targets: Vec::new(),
+            invalid_host: None,
+            strategy: Strategy::Failover,
+            failback_after: None,
+            settings: TcpStreamSettings::default(),
+            resolver: Arc::new(TokioResolver),
+            policy: None,
+            stable_period: None,
+            handshake: None,
+            classifier: None,
+            eof_policy: EofPolicy::Return,
+            max_attempts: None,
+            give_up_after: None,
+            replay: None,
+            queue: None,
+            spool: None,
+            callbacks: Vec::new(),
+        }
+    }
+
+    /// See [RetryingStream::set_name].
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Add target. First added target is the primary one.
+    pub fn target<T: Into<TcpTarget>>(mut self, target: T) -> Self {
+        self.targets.push(target.into());
+        self
+    }
+
+    /// Add target parsed from `ip:port` or `host:port`, see [TcpTarget].
+    pub fn host(mut self, target: &str) -> Self {
+        match target.parse() {
+            Ok(target) => self.targets.push(target),
+            Err(_) => {
+                self.invalid_host.get_or_insert_with(|| target.to_owned());
+            }
+        }
+        self
+    }
+
+    /// Add all `targets`.
+    pub fn targets<I: IntoIterator<Item = TcpTarget>>(mut self, targets: I) -> Self {
+        self.targets.extend(targets);
+        self
+    }
+
+    /// How next target is selected when there is more than one. Default is
+    /// [Strategy::Failover].
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// See [TargetSet::set_failback_after]. Require more than one target.
+    pub fn failback_after(mut self, failback_after: Duration) -> Self {
+        self.failback_after = Some(failback_after);
+        self
+    }
+
+    /// Replace all socket options and timeouts.
+    pub fn settings(mut self, settings: TcpStreamSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// See [TcpStreamSettings::nodelay].
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.settings.nodelay = nodelay;
+        self
+    }
+
+    /// See [TcpStreamSettings::keepalive].
+    pub fn keepalive(mut self, keepalive: Duration) -> Self {
+        self.settings.keepalive = Some(keepalive);
+        self
+    }
+
+    /// See [TcpStreamSettings::connect_timeout].
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// See [TcpStreamSettings::connect_timeout_max].
+    pub fn connect_timeout_max(mut self, max: Duration) -> Self {
+        self.settings.connect_timeout_max = Some(max);
+        self
+    }
+
+    /// See [TcpStreamSettings::bind_addr].
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.settings.bind_addr = Some(addr);
+        self
+    }
+
+    /// See [TcpConnector::set_resolver].
+    pub fn resolver<R: Resolver + 'static>(mut self, resolver: R) -> Self {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// See [RetryingStream::set_reconnect_policy].
+    pub fn reconnect_policy<P: ReconnectPolicy + 'static>(mut self, policy: P) -> Self {
+        self.policy = Some(Box::new(policy));
+        self
+    }
+
+    /// See [RetryingStream::set_stable_period].
+    pub fn stable_period(mut self, stable_period: Duration) -> Self {
+        self.stable_period = Some(stable_period);
+        self
+    }
+
+    /// See [RetryingStream::set_handshake].
+    pub fn handshake<H: Handshake<TcpStream> + 'static>(mut self, handshake: H) -> Self {
+        self.handshake = Some(Box::new(handshake));
+        self
+    }
+
+    /// See [RetryingStream::set_error_classifier].
+    pub fn error_classifier<E: ErrorClassifier + 'static>(mut self, classifier: E) -> Self {
+        self.classifier = Some(Box::new(classifier));
+        self
+    }
+
+    /// See [RetryingStream::set_eof_policy].
+    pub fn eof_policy(mut self, eof_policy: EofPolicy) -> Self {
+        self.eof_policy = eof_policy;
+        self
+    }
+
+    /// See [RetryingStream::set_max_attempts]. Must be greater than 0.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// See [RetryingStream::set_give_up_after].
+    pub fn give_up_after(mut self, give_up_after: Duration) -> Self {
+        self.give_up_after = Some(give_up_after);
+        self
+    }
+
+    /// See [RetryingStream::set_replay_policy]. Capacity must be greater than 0.
+    pub fn replay(mut self, policy: ReplayPolicy) -> Self {
+        self.replay = Some(policy);
+        self
+    }
+
+    /// See [RetryingStream::set_write_queue]. Capacity must be greater than 0.
+    pub fn write_queue(mut self, policy: QueuePolicy) -> Self {
+        self.queue = Some(policy);
+        self
+    }
+
+    /// See [RetryingStream::set_spool]. Can't be combined with
+    /// [write_queue](TcpStreamBuilder::write_queue).
+    pub fn spool(mut self, spool: Spool) -> Self {
+        self.spool = Some(spool);
+        self
+    }
+
+    /// See [RetryingStream::on_event]. Can be called many times.
+    pub fn on_event<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&TcpEvent) + Send + 'static,
+    {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Validate settings and create stream. Connection is started on first poll.
+    pub fn build(self) -> Result<RetryingTcpStream, BuildError> {
+        if let Some(host) = self.invalid_host {
+            return Err(BuildError::InvalidTarget(host));
+        }
+        if self.targets.is_empty() {
+            return Err(BuildError::NoTarget);
+        }
+        self.validate_settings()?;
+        if self.max_attempts == Some(0) {
+            return Err(BuildError::Conflict("max_attempts is zero"));
+        }
+        if self.replay.is_some_and(|replay| replay.capacity == 0) {
+            return Err(BuildError::Conflict("replay capacity is zero"));
+        }
+        if self.queue.is_some_and(|queue| queue.capacity == 0) {
+            return Err(BuildError::Conflict("write queue capacity is zero"));
+        }
+        if self.queue.is_some() && self.spool.is_some() {
+            return Err(BuildError::Conflict("write_queue and spool are both set"));
+        }
+        let mut targets = TargetSet::new(self.targets, self.strategy)
+            .map_err(|err| BuildError::InvalidStrategy(err.to_string()))?;
+        if self.failback_after.is_some() && targets.targets().len() == 1 {
+            return Err(BuildError::Conflict(
+                "failback_after requires more than one target",
+            ));
+        }
+        if let Some(bind_addr) = self.settings.bind_addr {
+            let mismatch = targets.targets().iter().any(|target| match target {
+                TcpTarget::Addr(addr) => addr.is_ipv6() != bind_addr.is_ipv6(),
+                TcpTarget::Host { .. } => false,
+            });
+            if mismatch {
+                return Err(BuildError::Conflict(
+                    "bind_addr and target have different address family",
+                ));
+            }
+        }
+        targets.set_failback_after(self.failback_after);
+
+        let mut connector = TcpConnector::new(self.settings);
+        connector.resolver = self.resolver;
+        let mut stream = RetryingStream::with_target_set(connector, targets);
+        if let Some(name) = self.name {
+            stream.set_name(name);
+        }
+        if let Some(policy) = self.policy {
+            stream.policy = policy;
+        }
+        if let Some(stable_period) = self.stable_period {
+            stream.stable_period = stable_period;
+        }
+        stream.handshake = self.handshake;
+        if let Some(classifier) = self.classifier {
+            stream.classifier = classifier;
+        }
+        stream.eof_policy = self.eof_policy;
+        stream.max_attempts = self.max_attempts;
+        stream.give_up_after = self.give_up_after;
+        stream.set_replay_policy(self.replay);
+        stream.set_write_queue(self.queue);
+        if self.spool.is_some() {
+            stream.set_spool(self.spool);
+        }
+        for callback in self.callbacks {
+            stream.observers.add_callback(callback, None);
+        }
+        Ok(stream)
+    }
+
+    fn validate_settings(&self) -> Result<(), BuildError> {
+        let settings = &self.settings;
+        match (settings.connect_timeout, settings.connect_timeout_max) {
+            (Some(timeout), _) if timeout.is_zero() => {
+                return Err(BuildError::Conflict("connect_timeout is zero"))
+            }
+            (None, Some(_)) => {
+                return Err(BuildError::Conflict(
+                    "connect_timeout_max requires connect_timeout",
+                ))
+            }
+            (Some(timeout), Some(max)) if max < timeout => {
+                return Err(BuildError::Conflict(
+                    "connect_timeout_max is smaller than connect_timeout",
+                ))
+            }
+            _ => {}
+        }
+        if settings.keepalive.is_none()
+            && (settings.keepalive_interval.is_some() || settings.keepalive_retries.is_some())
+        {
+            return Err(BuildError::Conflict(
+                "keepalive_interval and keepalive_retries require keepalive",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Error returned by [TcpStreamBuilder::build].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// No target was added.
+    NoTarget,
+    /// Target is not `ip:port` nor `host:port`.
+    InvalidTarget(String),
+    /// [Strategy] doesn't match targets.
+    InvalidStrategy(String),
+    /// Options that can't be used together or are out of range.
+    Conflict(&'static str),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::NoTarget => f.write_str("no target"),
+            BuildError::InvalidTarget(target) => write!(f, "invalid target {:?}", target),
+            BuildError::InvalidStrategy(msg) => write!(f, "invalid strategy: {}", msg),
+            BuildError::Conflict(msg) => write!(f, "invalid settings: {}", msg),
+        }
+    }
+}
+
+impl error::Error for BuildError {}
+
+impl From<BuildError> for Error {
+    fn from(err: BuildError) -> Self {
+        Error::new(ErrorKind::InvalidInput, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::QueueOverflow;
+    use crate::replay::Overflow;
+    use crate::spool::SyncPolicy;
+
+    type Configure = Box<dyn FnOnce(TcpStreamBuilder) -> TcpStreamBuilder>;
+
+    fn build(configure: Configure) -> Result<RetryingTcpStream, BuildError> {
+        configure(RetryingTcpStream::builder()).build()
+    }
+
+    #[test]
+    fn invalid_combinations_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool_path = dir.path().join("spool");
+        let v4: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        let v6: SocketAddr = "[::1]:80".parse().unwrap();
+        let secs = Duration::from_secs;
+        let cases: Vec<(Configure, BuildError)> = vec![
+            (Box::new(move |b| b), BuildError::NoTarget),
+            (
+                Box::new(move |b| b.target(v4).host("::1")),
+                BuildError::InvalidTarget("::1".to_owned()),
+            ),
+            (
+                Box::new(move |b| {
+                    b.target(v4)
+                        .target(v6)
+                        .strategy(Strategy::Weighted(vec![1]))
+                }),
+                BuildError::InvalidStrategy(
+                    "number of weights doesn't match number of targets".to_owned(),
+                ),
+            ),
+            (
+                Box::new(move |b| b.target(v4).max_attempts(0)),
+                BuildError::Conflict("max_attempts is zero"),
+            ),
+            (
+                Box::new(move |b| b.target(v4).replay(ReplayPolicy::new(0, Overflow::Block))),
+                BuildError::Conflict("replay capacity is zero"),
+            ),
+            (
+                Box::new(move |b| {
+                    b.target(v4)
+                        .write_queue(QueuePolicy::new(0, QueueOverflow::Block))
+                }),
+                BuildError::Conflict("write queue capacity is zero"),
+            ),
+            (
+                Box::new(move |b| {
+                    let policy = QueuePolicy::new(10, QueueOverflow::Block);
+                    let spool = Spool::open(&spool_path, policy, SyncPolicy::Never).unwrap();
+                    b.target(v4).write_queue(policy).spool(spool)
+                }),
+                BuildError::Conflict("write_queue and spool are both set"),
+            ),
+            (
+                Box::new(move |b| b.target(v4).failback_after(secs(1))),
+                BuildError::Conflict("failback_after requires more than one target"),
+            ),
+            (
+                Box::new(move |b| {
+                    b.target(v4)
+                        .target(v6)
+                        .bind_addr("0.0.0.0:0".parse().unwrap())
+                }),
+                BuildError::Conflict("bind_addr and target have different address family"),
+            ),
+            (
+                Box::new(move |b| b.target(v4).connect_timeout(Duration::ZERO)),
+                BuildError::Conflict("connect_timeout is zero"),
+            ),
+            (
+                Box::new(move |b| b.target(v4).connect_timeout_max(secs(1))),
+                BuildError::Conflict("connect_timeout_max requires connect_timeout"),
+            ),
+            (
+                Box::new(move |b| {
+                    b.target(v4)
+                        .connect_timeout(secs(2))
+                        .connect_timeout_max(secs(1))
+                }),
+                BuildError::Conflict("connect_timeout_max is smaller than connect_timeout"),
+            ),
+            (
+                Box::new(move |b| {
+                    b.target(v4).settings(TcpStreamSettings {
+                        keepalive_retries: Some(3),
+                        ..TcpStreamSettings::default()
+                    })
+                }),
+                BuildError::Conflict("keepalive_interval and keepalive_retries require keepalive"),
+            ),
+        ];
+        for (configure, expected) in cases {
+            assert_eq!(build(configure).err(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn valid_combinations_are_accepted() {
+        let v4: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        let v6: SocketAddr = "[::1]:80".parse().unwrap();
+        let secs = Duration::from_secs;
+        let cases: Vec<Configure> = vec![
+            Box::new(move |b| b.target(v4)),
+            // family of host name is known only after resolving it
+            Box::new(move |b| b.host("localhost:80").bind_addr("[::]:0".parse().unwrap())),
+            Box::new(move |b| b.target(v4).target(v6).failback_after(secs(1))),
+            Box::new(move |b| {
+                b.target(v4)
+                    .connect_timeout(secs(1))
+                    .connect_timeout_max(secs(1))
+                    .keepalive(secs(60))
+                    .max_attempts(1)
+            }),
+        ];
+        for configure in cases {
+            assert!(build(configure).is_ok());
+        }
+    }
+}
@@ -0,0 +1,732 @@
+// Session protocol shared by client Session and server side.
+//
+// Every connection starts with both sides sending HELLO: magic, session id and number of bytes
+// received from peer so far. Client sends id 0 to open new session, server answers with id of
+// new session, or 0 when it doesn't know the one client wants to resume. Then both sides
+// exchange frames:
+//   DATA  (1) seq: u64, len: u32, payload
+//   ACK   (2) seq: u64, all bytes before seq were read by application
+//   CLOSE (3) seq: u64, there is no data after seq
+// Sequence numbers count payload bytes since session start, CLOSE takes one number. Sender keeps
+// bytes until they are acknowledged and on new connection resends them from the position peer
+// sent in HELLO.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll, Wake, Waker};
+use std::time::Duration;
+
+use tokio::io::{Error, ErrorKind, ReadBuf};
+
+use crate::event::clone_error;
+
+pub(crate) const MAGIC: &[u8; 4] = b"RTS1";
+pub(crate) const HELLO_LEN: usize = 20;
+// connection whose peer doesn't send HELLO in time is dropped
+pub(crate) const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DATA: u8 = 1;
+const ACK: u8 = 2;
+const CLOSE: u8 = 3;
+const DATA_HEADER_LEN: usize = 13;
+const ACK_LEN: usize = 9;
+// largest payload of single DATA frame
+const MAX_PAYLOAD: usize = 64 * 1024;
+// stop encoding DATA frames when that much is waiting for transport
+const OUTPUT_HIGH_WATER: usize = 2 * MAX_PAYLOAD;
+
+/// Default limit of bytes not acknowledged by peer.
+pub const DEFAULT_MAX_UNACKED: usize = 1024 * 1024;
+
+// First message on every connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Hello {
+    pub(crate) session_id: u64,
+    pub(crate) received: u64,
+}
+
+impl Hello {
+    pub(crate) fn encode(&self) -> [u8; HELLO_LEN] {
+        let mut buf = [0; HELLO_LEN];
+        buf[..4].copy_from_slice(MAGIC);
+        buf[4..12].copy_from_slice(&self.session_id.to_be_bytes());
+        buf[12..].copy_from_slice(&self.received.to_be_bytes());
+        buf
+    }
+
+    pub(crate) fn decode(buf: &[u8; HELLO_LEN]) -> Result<Self, Error> {
+        if &buf[..4] != MAGIC {
+            return Err(protocol_error("peer doesn't speak session protocol"));
+        }
+        Ok(Self {
+            session_id: read_u64(&buf[4..]),
+            received: read_u64(&buf[12..]),
+        })
+    }
+}
+
+// Data of one session independent of connection it is transferred over
+pub(crate) struct Channel {
+    // bytes not acknowledged by peer, first one has sequence number `send_base`
+    unacked: VecDeque<u8>,
+    send_base: u64,
+    // sequence number of next byte to send on current connection
+    send_next: u64,
+    max_unacked: usize,
+    // application shut down writing, CLOSE follows the data
+    closing: bool,
+    close_sent: bool,
+    close_acked: bool,
+    // bytes received and not read by application
+    incoming: VecDeque<u8>,
+    received: u64,
+    // last acknowledged sequence number sent to peer
+    ack_sent: u64,
+    peer_closed: bool,
+    // encoded frames not written to transport yet
+    output: Vec<u8>,
+    output_pos: usize,
+    // beginning of frame not received whole yet
+    input: Vec<u8>,
+}
+
+impl Channel {
+    pub(crate) fn new(max_unacked: usize) -> Self {
+        Self {
+            unacked: VecDeque::new(),
+            send_base: 0,
+            send_next: 0,
+            max_unacked,
+            closing: false,
+            close_sent: false,
+            close_acked: false,
+            incoming: VecDeque::new(),
+            received: 0,
+            ack_sent: 0,
+            peer_closed: false,
+            output: Vec::new(),
+            output_pos: 0,
+            input: Vec::new(),
+        }
+    }
+
+    pub(crate) fn set_max_unacked(&mut self, max_unacked: usize) {
+        self.max_unacked = max_unacked;
+    }
+
+    // Number of bytes received from peer, including CLOSE
+    pub(crate) fn received(&self) -> u64 {
+        self.received
+    }
+
+    fn data_end(&self) -> u64 {
+        self.send_base + self.unacked.len() as u64
+    }
+
+    // Sequence number after everything application wrote, including CLOSE
+    fn send_end(&self) -> u64 {
+        self.data_end() + u64::from(self.closing)
+    }
+
+    // Position application read up to. CLOSE counts when everything before it was read.
+    fn consumed(&self) -> u64 {
+        self.received - u64::from(self.peer_closed) - self.incoming.len() as u64
+            + u64::from(self.is_eof())
+    }
+
+    // Copy application data, return how much fit
+    pub(crate) fn write(&mut self, buf: &[u8]) -> usize {
+        let n = buf
+            .len()
+            .min(self.max_unacked.saturating_sub(self.unacked.len()));
+        self.unacked.extend(&buf[..n]);
+        n
+    }
+
+    pub(crate) fn close(&mut self) {
+        self.closing = true;
+    }
+
+    pub(crate) fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    // Peer acknowledged CLOSE, so it read everything
+    pub(crate) fn is_close_acked(&self) -> bool {
+        self.close_acked
+    }
+
+    // Everything written by application was passed to transport
+    pub(crate) fn is_sent(&self) -> bool {
+        self.send_next == self.data_end()
+            && (!self.closing || self.close_sent)
+            && self.output_pos == self.output.len()
+            && self.consumed() == self.ack_sent
+    }
+
+    // Copy received data to `buf`. Return false if there is none.
+    pub(crate) fn read(&mut self, buf: &mut ReadBuf<'_>) -> bool {
+        if self.incoming.is_empty() {
+            return false;
+        }
+        let n = buf.remaining().min(self.incoming.len());
+        let (front, back) = self.incoming.as_slices();
+        let from_front = n.min(front.len());
+        buf.put_slice(&front[..from_front]);
+        buf.put_slice(&back[..n - from_front]);
+        self.incoming.drain(..n);
+        true
+    }
+
+    // Peer closed and everything it sent was read
+    pub(crate) fn is_eof(&self) -> bool {
+        self.peer_closed && self.incoming.is_empty()
+    }
+
+    // New connection. `peer_received` is what peer sent in HELLO.
+    pub(crate) fn resume(&mut self, peer_received: u64) -> Result<(), Error> {
+        if peer_received < self.send_base || peer_received > self.send_end() {
+            return Err(protocol_error("peer resumes from unknown position"));
+        }
+        self.acknowledge(peer_received);
+        self.send_next = peer_received.min(self.data_end());
+        self.close_sent = self.close_acked;
+        self.ack_sent = self.consumed();
+        self.output.clear();
+        self.output_pos = 0;
+        self.input.clear();
+        Ok(())
+    }
+
+    // Answer HELLO of new connection, before any frame
+    pub(crate) fn send_hello(&mut self, session_id: u64) {
+        let hello = Hello {
+            session_id,
+            received: self.received,
+        };
+        self.output.extend_from_slice(&hello.encode());
+    }
+
+    fn acknowledge(&mut self, seq: u64) {
+        let data = seq.min(self.data_end());
+        if data > self.send_base {
+            self.unacked.drain(..(data - self.send_base) as usize);
+            self.send_base = data;
+        }
+        if self.closing && seq == self.send_end() {
+            self.close_acked = true;
+        }
+    }
+
+    // Decode frames from bytes read from transport
+    pub(crate) fn feed(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let mut input = std::mem::take(&mut self.input);
+        input.extend_from_slice(bytes);
+        let mut pos = 0;
+        let res = loop {
+            match self.decode(&input[pos..]) {
+                Ok(Some(len)) => pos += len,
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        input.drain(..pos);
+        self.input = input;
+        res
+    }
+
+    // Handle first frame of `buf`, return its length or None if it is not complete
+    fn decode(&mut self, buf: &[u8]) -> Result<Option<usize>, Error> {
+        let kind = match buf.first() {
+            Some(kind) => *kind,
+            None => return Ok(None),
+        };
+        let header_len = if kind == DATA {
+            DATA_HEADER_LEN
+        } else {
+            ACK_LEN
+        };
+        if buf.len() < header_len {
+            return Ok(None);
+        }
+        let seq = read_u64(&buf[1..]);
+        match kind {
+            DATA => {
+                let len = u32::from_be_bytes(buf[9..13].try_into().unwrap()) as usize;
+                if len > MAX_PAYLOAD {
+                    return Err(protocol_error("DATA frame too long"));
+                }
+                if buf.len() < header_len + len {
+                    return Ok(None);
+                }
+                if seq != self.received || self.peer_closed {
+                    return Err(protocol_error("DATA frame out of order"));
+                }
+                self.incoming.extend(&buf[header_len..header_len + len]);
+                self.received += len as u64;
+                Ok(Some(header_len + len))
+            }
+            ACK => {
+                // after resume ACK can be behind position peer sent in HELLO
+                if seq > self.send_end() {
+                    return Err(protocol_error("ACK of unknown data"));
+                }
+                self.acknowledge(seq);
+                Ok(Some(header_len))
+            }
+            CLOSE => {
+                if seq != self.received || self.peer_closed {
+                    return Err(protocol_error("CLOSE frame out of order"));
+                }
+                self.peer_closed = true;
+                self.received += 1;
+                Ok(Some(header_len))
+            }
+            _ => Err(protocol_error("unknown frame")),
+        }
+    }
+
+    // Frames to write to transport
+    pub(crate) fn output(&mut self) -> &[u8] {
+        if self.output_pos == self.output.len() {
+            self.output.clear();
+            self.output_pos = 0;
+        }
+        let consumed = self.consumed();
+        if consumed > self.ack_sent {
+            self.output.push(ACK);
+            self.output.extend_from_slice(&consumed.to_be_bytes());
+            self.ack_sent = consumed;
+        }
+        while self.send_next < self.data_end() && self.output.len() < OUTPUT_HIGH_WATER {
+            let offset = (self.send_next - self.send_base) as usize;
+            let len = (self.unacked.len() - offset).min(MAX_PAYLOAD);
+            self.output.push(DATA);
+            self.output.extend_from_slice(&self.send_next.to_be_bytes());
+            self.output.extend_from_slice(&(len as u32).to_be_bytes());
+            self.output.extend(self.unacked.range(offset..offset + len));
+            self.send_next += len as u64;
+        }
+        if self.closing && !self.close_sent && self.send_next == self.data_end() {
+            self.output.push(CLOSE);
+            self.output
+                .extend_from_slice(&self.data_end().to_be_bytes());
+            self.close_sent = true;
+        }
+        &self.output[self.output_pos..]
+    }
+
+    pub(crate) fn advance_output(&mut self, n: usize) {
+        self.output_pos += n;
+    }
+}
+
+// One end of a session, client or server. Implementations differ only in how they get
+// connections, the rest of reading and writing is shared.
+pub(crate) trait Endpoint {
+    fn channel(&mut self) -> &mut Channel;
+
+    fn wakers(&self) -> &Wakers;
+
+    // Error that ended the session
+    fn failed(&mut self) -> &mut Option<Error>;
+
+    // Apply HELLO of new connection, return true if there was one
+    fn resume(&mut self) -> Result<bool, Error>;
+
+    // Write frames to transport until everything is sent
+    fn poll_send(&mut self) -> Poll<Result<(), Error>>;
+
+    // Read frames from transport, Ready after some were decoded or connection was resumed
+    fn poll_recv(&mut self) -> Poll<Result<(), Error>>;
+
+    // Remember error, session can't continue
+    fn fail<T>(&mut self, res: Result<T, Error>) -> Result<T, Error> {
+        if let Err(err) = &res {
+            *self.failed() = Some(clone_error(err));
+        }
+        res
+    }
+
+    // Return error when session failed, apply HELLO of new connection. Return true if there was
+    // one.
+    fn check(&mut self) -> Result<bool, Error> {
+        if let Some(err) = self.failed() {
+            return Err(clone_error(err));
+        }
+        let res = self.resume();
+        self.fail(res)
+    }
+
+    fn poll_read_data(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        Wakers::register(&self.wakers().read, cx.waker());
+        loop {
+            self.check()?;
+            let channel = self.channel();
+            if channel.read(buf) || channel.is_eof() {
+                // acknowledge what was read, errors are returned by next call
+                let _ = self.poll_send();
+                return Poll::Ready(Ok(()));
+            }
+            if let Poll::Ready(Err(err)) = self.poll_send() {
+                return Poll::Ready(Err(err));
+            }
+            ready!(self.poll_recv())?;
+        }
+    }
+
+    fn poll_write_data(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        Wakers::register(&self.wakers().write, cx.waker());
+        loop {
+            self.check()?;
+            if self.channel().is_closing() {
+                return Poll::Ready(Err(Error::new(
+                    ErrorKind::BrokenPipe,
+                    "session was shut down",
+                )));
+            }
+            let n = self.channel().write(buf);
+            if n > 0 || buf.is_empty() {
+                // data is kept by the session, errors are returned by next call
+                let _ = self.poll_send();
+                return Poll::Ready(Ok(n));
+            }
+            // wait for acknowledgements
+            if let Poll::Ready(Err(err)) = self.poll_send() {
+                return Poll::Ready(Err(err));
+            }
+            ready!(self.poll_recv())?;
+        }
+    }
+
+    // Send end of data and wait until peer reads all of it
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Wakers::register(&self.wakers().write, cx.waker());
+        self.channel().close();
+        loop {
+            self.check()?;
+            if self.channel().is_close_acked() {
+                return Poll::Ready(Ok(()));
+            }
+            if let Poll::Ready(Err(err)) = self.poll_send() {
+                return Poll::Ready(Err(err));
+            }
+            ready!(self.poll_recv())?;
+        }
+    }
+}
+
+// Tasks blocked in reading and writing. Both read frames from transport, e.g. writer waits for
+// acknowledgements, but transport keeps only the last waker, so it gets one waking them all.
+#[derive(Default)]
+pub(crate) struct Wakers {
+    pub(crate) read: Mutex<Option<Waker>>,
+    pub(crate) write: Mutex<Option<Waker>>,
+}
+
+impl Wakers {
+    pub(crate) fn register(slot: &Mutex<Option<Waker>>, waker: &Waker) {
+        let mut slot = slot.lock().unwrap();
+        if !slot.as_ref().is_some_and(|old| old.will_wake(waker)) {
+            *slot = Some(waker.clone());
+        }
+    }
+}
+
+impl Wake for Wakers {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        for slot in [&self.read, &self.write] {
+            if let Some(waker) = slot.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    u64::from_be_bytes(buf[..8].try_into().unwrap())
+}
+
+pub(crate) fn protocol_error(msg: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("session protocol error: {}", msg),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Move everything `from` has to send to `to`
+    fn pump(from: &mut Channel, to: &mut Channel) -> Result<(), Error> {
+        let output = from.output().to_vec();
+        from.advance_output(output.len());
+        to.feed(&output)
+    }
+
+    // Output of `from` is lost, e.g. connection broke
+    fn lose(from: &mut Channel) {
+        let len = from.output().len();
+        from.advance_output(len);
+    }
+
+    fn read_all(channel: &mut Channel) -> Vec<u8> {
+        let mut buf = [0; 1024];
+        let mut data = Vec::new();
+        loop {
+            let mut read_buf = ReadBuf::new(&mut buf);
+            if !channel.read(&mut read_buf) {
+                return data;
+            }
+            data.extend_from_slice(read_buf.filled());
+        }
+    }
+
+    fn frame(kind: u8, seq: u64, payload: Option<&[u8]>) -> Vec<u8> {
+        let mut frame = vec![kind];
+        frame.extend_from_slice(&seq.to_be_bytes());
+        if let Some(payload) = payload {
+            frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            frame.extend_from_slice(payload);
+        }
+        frame
+    }
+
+    #[test]
+    fn hello_roundtrip() {
+        let hello = Hello {
+            session_id: 7,
+            received: u64::MAX,
+        };
+        assert_eq!(Hello::decode(&hello.encode()).unwrap(), hello);
+        let mut bad = hello.encode();
+        bad[0] = b'X';
+        assert_eq!(
+            Hello::decode(&bad).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn data_is_acknowledged_after_read() {
+        let mut a = Channel::new(DEFAULT_MAX_UNACKED);
+        let mut b = Channel::new(DEFAULT_MAX_UNACKED);
+        assert_eq!(a.write(b"hello"), 5);
+        assert!(!a.is_sent());
+        pump(&mut a, &mut b).unwrap();
+        assert!(a.is_sent());
+        assert_eq!(b.received(), 5);
+        // nothing to acknowledge before application reads
+        assert!(b.output().is_empty());
+        assert_eq!(read_all(&mut b), b"hello");
+        assert!(!b.is_sent());
+        pump(&mut b, &mut a).unwrap();
+        assert!(b.is_sent());
+        assert_eq!(a.send_base, 5);
+        assert!(a.unacked.is_empty());
+    }
+
+    #[test]
+    fn partial_frames() {
+        let mut a = Channel::new(DEFAULT_MAX_UNACKED);
+        let mut b = Channel::new(DEFAULT_MAX_UNACKED);
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        assert_eq!(a.write(&data), data.len());
+        a.close();
+        let mut output = Vec::new();
+        while !a.is_sent() {
+            let chunk = a.output().to_vec();
+            a.advance_output(chunk.len());
+            output.extend(chunk);
+        }
+        // frames split at every possible position
+        for chunk in output.chunks(7) {
+            b.feed(chunk).unwrap();
+        }
+        assert_eq!(read_all(&mut b), data);
+        assert!(b.is_eof());
+    }
+
+    #[test]
+    fn write_is_limited_by_max_unacked() {
+        let mut a = Channel::new(10);
+        let mut b = Channel::new(10);
+        assert_eq!(a.write(&[1; 8]), 8);
+        assert_eq!(a.write(&[2; 8]), 2);
+        assert_eq!(a.write(&[3; 8]), 0);
+        pump(&mut a, &mut b).unwrap();
+        // sent, but not acknowledged
+        assert_eq!(a.write(&[3; 8]), 0);
+        read_all(&mut b);
+        pump(&mut b, &mut a).unwrap();
+        assert_eq!(a.write(&[3; 8]), 8);
+    }
+
+    #[test]
+    fn out_of_order_data() {
+        let mut b = Channel::new(DEFAULT_MAX_UNACKED);
+        b.feed(&frame(DATA, 0, Some(b"abc"))).unwrap();
+        // repeated
+        let err = b.feed(&frame(DATA, 0, Some(b"abc"))).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let mut b = Channel::new(DEFAULT_MAX_UNACKED);
+        // gap
+        assert!(b.feed(&frame(DATA, 1, Some(b"abc"))).is_err());
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let mut b = Channel::new(DEFAULT_MAX_UNACKED);
+        let mut long = frame(DATA, 0, None);
+        long.extend_from_slice(&(MAX_PAYLOAD as u32 + 1).to_be_bytes());
+        // rejected before payload arrives
+        assert!(b.feed(&long).is_err());
+        let mut b = Channel::new(DEFAULT_MAX_UNACKED);
+        assert!(b.feed(&frame(9, 0, None)).is_err());
+        let mut b = Channel::new(DEFAULT_MAX_UNACKED);
+        b.feed(&frame(CLOSE, 0, None)).unwrap();
+        assert!(b.feed(&frame(DATA, 1, Some(b"abc"))).is_err());
+        assert!(b.feed(&frame(CLOSE, 1, None)).is_err());
+    }
+
+    #[test]
+    fn ack_beyond_send_end() {
+        let mut a = Channel::new(DEFAULT_MAX_UNACKED);
+        a.write(b"abc");
+        assert!(a.feed(&frame(ACK, 4, None)).is_err());
+        let mut a = Channel::new(DEFAULT_MAX_UNACKED);
+        a.write(b"abc");
+        a.feed(&frame(ACK, 2, None)).unwrap();
+        assert_eq!(a.send_base, 2);
+        // old ACK after newer one changes nothing
+        a.feed(&frame(ACK, 1, None)).unwrap();
+        assert_eq!(a.send_base, 2);
+        // CLOSE takes one more number
+        a.close();
+        a.feed(&frame(ACK, 4, None)).unwrap();
+        assert!(a.is_close_acked());
+    }
+
+    #[test]
+    fn close_counts_in_consumed() {
+        let mut a = Channel::new(DEFAULT_MAX_UNACKED);
+        let mut b = Channel::new(DEFAULT_MAX_UNACKED);
+        a.write(b"ab");
+        a.close();
+        pump(&mut a, &mut b).unwrap();
+        assert_eq!(b.received(), 3);
+        assert_eq!(b.consumed(), 0);
+        assert!(!b.is_eof());
+        assert_eq!(read_all(&mut b), b"ab");
+        assert!(b.is_eof());
+        assert_eq!(b.consumed(), 3);
+        pump(&mut b, &mut a).unwrap();
+        assert_eq!(b.ack_sent, 3);
+        assert!(a.is_close_acked());
+    }
+
+    #[test]
+    fn close_waits_for_data() {
+        let mut a = Channel::new(DEFAULT_MAX_UNACKED);
+        let mut b = Channel::new(DEFAULT_MAX_UNACKED);
+        a.close();
+        assert!(a.is_closing());
+        pump(&mut a, &mut b).unwrap();
+        assert!(b.is_eof());
+        pump(&mut b, &mut a).unwrap();
+        assert!(a.is_close_acked());
+    }
+
+    #[test]
+    fn resume_resends_lost_data() {
+        let mut a = Channel::new(DEFAULT_MAX_UNACKED);
+        let mut b = Channel::new(DEFAULT_MAX_UNACKED);
+        a.write(b"abc");
+        pump(&mut a, &mut b).unwrap();
+        a.write(b"def");
+        a.close();
+        lose(&mut a);
+        assert!(a.is_sent());
+        // new connection, b tells what it received
+        a.resume(b.received()).unwrap();
+        assert!(!a.is_sent());
+        b.resume(a.received()).unwrap();
+        pump(&mut a, &mut b).unwrap();
+        assert_eq!(read_all(&mut b), b"abcdef");
+        assert!(b.is_eof());
+    }
+
+    #[test]
+    fn resume_drops_partial_frame() {
+        let mut a = Channel::new(DEFAULT_MAX_UNACKED);
+        let mut b = Channel::new(DEFAULT_MAX_UNACKED);
+        a.write(b"abcdef");
+        let output = a.output().to_vec();
+        a.advance_output(output.len());
+        b.feed(&output[..output.len() - 2]).unwrap();
+        assert_eq!(b.received(), 0);
+        a.resume(b.received()).unwrap();
+        b.resume(a.received()).unwrap();
+        pump(&mut a, &mut b).unwrap();
+        assert_eq!(read_all(&mut b), b"abcdef");
+    }
+
+    #[test]
+    fn resume_acknowledges() {
+        let mut a = Channel::new(DEFAULT_MAX_UNACKED);
+        a.write(b"abcdef");
+        lose(&mut a);
+        // peer received part of data, but its ACK was lost
+        a.resume(4).unwrap();
+        assert_eq!(a.send_base, 4);
+        assert_eq!(a.send_next, 4);
+        // ACK behind HELLO position is fine
+        a.feed(&frame(ACK, 2, None)).unwrap();
+        assert_eq!(a.send_base, 4);
+    }
+
+    #[test]
+    fn resume_from_unknown_position() {
+        let mut a = Channel::new(DEFAULT_MAX_UNACKED);
+        a.write(b"abcdef");
+        assert!(a.resume(7).is_err());
+        a.resume(4).unwrap();
+        // acknowledged bytes are gone
+        assert!(a.resume(3).is_err());
+        a.close();
+        a.resume(7).unwrap();
+        assert!(a.is_close_acked());
+    }
+
+    #[test]
+    fn send_hello_precedes_frames() {
+        let mut a = Channel::new(DEFAULT_MAX_UNACKED);
+        let mut b = Channel::new(DEFAULT_MAX_UNACKED);
+        b.write(b"xy");
+        pump(&mut b, &mut a).unwrap();
+        a.write(b"abc");
+        a.resume(0).unwrap();
+        a.send_hello(42);
+        let output = a.output().to_vec();
+        let hello: [u8; HELLO_LEN] = output[..HELLO_LEN].try_into().unwrap();
+        assert_eq!(
+            Hello::decode(&hello).unwrap(),
+            Hello {
+                session_id: 42,
+                received: 2
+            }
+        );
+        b.feed(&output[HELLO_LEN..]).unwrap();
+        assert_eq!(read_all(&mut b), b"abc");
+    }
+}
@@ -0,0 +1,132 @@
+//! Deciding what [RetryingStream](crate::RetryingStream) does with I/O error.
+
+use tokio::io::{Error, ErrorKind};
+
+/// What to do with I/O error returned by connected transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorAction {
+    /// Drop the connection and reconnect. Error is returned to the caller.
+    Retry,
+    /// Return error to the caller and keep the connection.
+    Surface,
+    /// Drop the connection and go to closed state. Stream never reconnects.
+    Fatal,
+}
+
+/// What to do when connected transport reads 0 bytes or writes 0 bytes of non-empty buffer,
+/// which means peer closed the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum EofPolicy {
+    /// Return `Ok(0)` like plain transport. Stream stays on closed connection. This is the
+    /// default.
+    #[default]
+    Return,
+    /// Reconnect and continue reading or writing on new connection. Caller never sees
+    /// end-of-stream.
+    Reconnect,
+    /// Reconnect and return `ConnectionReset` error to the caller.
+    Error,
+}
+
+/// Map I/O error to [ErrorAction].
+///
+/// Used for errors from `poll_read`, `poll_write`, `poll_flush`, `poll_read_ready`,
+/// `poll_write_ready` and `poll_peek`. Connect and handshake errors always lead to reconnect.
+///
+/// Implemented for closures `Fn(&Error) -> ErrorAction`.
+pub trait ErrorClassifier: Send {
+    fn classify(&self, error: &Error) -> ErrorAction;
+}
+
+impl<F> ErrorClassifier for F
+where
+    F: Fn(&Error) -> ErrorAction + Send,
+{
+    fn classify(&self, error: &Error) -> ErrorAction {
+        self(error)
+    }
+}
+
+/// Classifier used when no other is set.
+///
+/// OS errors reset the connection, except `EINTR`, `EAGAIN`, `ENOBUFS` and `ENOMEM` which are
+/// temporary and only surfaced. Errors without OS code, e.g. from transport wrapper or
+/// application, reset the connection only when their kind says it is broken
+/// (`ConnectionReset`, `BrokenPipe`, `UnexpectedEof`, ...). Nothing is fatal.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultClassifier;
+
+impl ErrorClassifier for DefaultClassifier {
+    fn classify(&self, error: &Error) -> ErrorAction {
+        match error.kind() {
+            ErrorKind::WouldBlock | ErrorKind::Interrupted => return ErrorAction::Surface,
+            _ => {}
+        }
+        match error.raw_os_error() {
+            Some(code) if is_temporary(code) => ErrorAction::Surface,
+            Some(_) => ErrorAction::Retry,
+            None => match error.kind() {
+                ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe
+                | ErrorKind::NotConnected
+                | ErrorKind::UnexpectedEof
+                | ErrorKind::TimedOut
+                | ErrorKind::WriteZero => ErrorAction::Retry,
+                _ => ErrorAction::Surface,
+            },
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_temporary(code: i32) -> bool {
+    code == libc::ENOBUFS || code == libc::ENOMEM
+}
+
+#[cfg(not(unix))]
+fn is_temporary(_code: i32) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_classifier_mapping() {
+        let cases = [
+            (ErrorKind::ConnectionReset.into(), ErrorAction::Retry),
+            (ErrorKind::BrokenPipe.into(), ErrorAction::Retry),
+            (ErrorKind::UnexpectedEof.into(), ErrorAction::Retry),
+            (ErrorKind::TimedOut.into(), ErrorAction::Retry),
+            (ErrorKind::WouldBlock.into(), ErrorAction::Surface),
+            (ErrorKind::Interrupted.into(), ErrorAction::Surface),
+            // e.g. decoding error of transport wrapper
+            (ErrorKind::InvalidData.into(), ErrorAction::Surface),
+            (Error::other("application error"), ErrorAction::Surface),
+        ];
+        for (error, action) in cases {
+            assert_eq!(DefaultClassifier.classify(&error), action, "{:?}", error);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn default_classifier_os_errors() {
+        let cases = [
+            (libc::ECONNRESET, ErrorAction::Retry),
+            (libc::EHOSTUNREACH, ErrorAction::Retry),
+            // kind doesn't matter for OS errors
+            (libc::EINVAL, ErrorAction::Retry),
+            (libc::EINTR, ErrorAction::Surface),
+            (libc::EAGAIN, ErrorAction::Surface),
+            (libc::ENOBUFS, ErrorAction::Surface),
+            (libc::ENOMEM, ErrorAction::Surface),
+        ];
+        for (code, action) in cases {
+            let error = Error::from_raw_os_error(code);
+            assert_eq!(DefaultClassifier.classify(&error), action, "{:?}", error);
+        }
+    }
+}
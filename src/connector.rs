@@ -0,0 +1,203 @@
+//! [Connector] describe how [RetryingStream](crate::RetryingStream) opens new transport.
+
+use std::fmt;
+use std::future::Future;
+
+use tokio::io::{AsyncRead, AsyncWrite, Error};
+
+/// Open connections to a target.
+///
+/// [RetryingStream](crate::RetryingStream) calls [connect](Connector::connect) every time it needs
+/// new transport: on creation and after each reset. Implement it to get auto reconnect for Unix
+/// sockets, TLS or test doubles. See [TcpConnector](crate::TcpConnector) for TCP.
+pub trait Connector {
+    /// Address connections are made to.
+    type Target: Clone + fmt::Display;
+    /// Connected transport.
+    type Transport: AsyncRead + AsyncWrite + Unpin;
+    /// Future resolving to connected transport.
+    type Future: Future<Output = Result<Self::Transport, Error>>;
+    /// Address of transport endpoint, reported in [Event::Connected](crate::event::Event).
+    type Addr: Clone + fmt::Debug;
+
+    /// Start connecting to `target`.
+    fn connect(&mut self, target: &Self::Target) -> Self::Future;
+
+    /// Called with each freshly connected transport before it is used.
+    ///
+    /// Returned error is treated like connect error.
+    fn configure(&mut self, _transport: &Self::Transport) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Return local and peer address of `transport`.
+    ///
+    /// Called after [configure](Connector::configure), returned error is treated like connect error.
+    fn addrs(&self, transport: &Self::Transport) -> Result<(Self::Addr, Self::Addr), Error>;
+
+    /// Return number of written bytes peer didn't acknowledge yet, `None` when unknown.
+    ///
+    /// Used by [replay buffer](crate::replay) to resend only lost bytes. Default returns `None`,
+    /// so everything retained is resent.
+    fn unacked_bytes(&self, _transport: &Self::Transport) -> Option<usize> {
+        None
+    }
+}
+
+// Connector for unit tests. Connect to a target succeeds when test added a peer for it with
+// `accept`, otherwise it fails right away with `ConnectionRefused`.
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::collections::{HashMap, VecDeque};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, Error, ErrorKind, ReadBuf};
+
+    use super::Connector;
+
+    // Result of next read or write of transport, set by test
+    #[derive(Debug, Clone, Copy)]
+    pub(crate) enum Fault {
+        Error(ErrorKind),
+        // peer closed: read returns no data, write returns Ok(0)
+        Eof,
+    }
+
+    #[derive(Default)]
+    struct Shared {
+        peers: HashMap<&'static str, VecDeque<MockTransport>>,
+        attempts: Vec<&'static str>,
+    }
+
+    #[derive(Clone, Default)]
+    pub(crate) struct MockConnector {
+        shared: Arc<Mutex<Shared>>,
+    }
+
+    impl MockConnector {
+        // Next connect to `target` succeeds, returned peer is the other end of transport
+        pub(crate) fn accept(&self, target: &'static str) -> Peer {
+            let (io, peer) = tokio::io::duplex(64 * 1024);
+            let fault = Arc::new(Mutex::new(None));
+            let unacked = Arc::new(Mutex::new(None));
+            let transport = MockTransport {
+                io,
+                target,
+                fault: fault.clone(),
+                unacked: unacked.clone(),
+            };
+            let mut shared = self.shared.lock().unwrap();
+            shared.peers.entry(target).or_default().push_back(transport);
+            Peer {
+                io: peer,
+                fault,
+                unacked,
+            }
+        }
+
+        // Targets of all connect attempts
+        pub(crate) fn attempts(&self) -> Vec<&'static str> {
+            self.shared.lock().unwrap().attempts.clone()
+        }
+    }
+
+    pub(crate) struct Peer {
+        pub(crate) io: DuplexStream,
+        fault: Arc<Mutex<Option<Fault>>>,
+        unacked: Arc<Mutex<Option<usize>>>,
+    }
+
+    impl Peer {
+        // Make next read or write of the transport fail
+        pub(crate) fn inject(&self, fault: Fault) {
+            *self.fault.lock().unwrap() = Some(fault);
+        }
+
+        // Number of bytes connector reports as not acknowledged, unknown by default
+        pub(crate) fn set_unacked(&self, unacked: Option<usize>) {
+            *self.unacked.lock().unwrap() = unacked;
+        }
+    }
+
+    pub(crate) struct MockTransport {
+        io: DuplexStream,
+        target: &'static str,
+        fault: Arc<Mutex<Option<Fault>>>,
+        unacked: Arc<Mutex<Option<usize>>>,
+    }
+
+    impl MockTransport {
+        fn take_fault(&self) -> Option<Fault> {
+            self.fault.lock().unwrap().take()
+        }
+    }
+
+    impl AsyncRead for MockTransport {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<Result<(), Error>> {
+            match self.take_fault() {
+                Some(Fault::Error(kind)) => Poll::Ready(Err(kind.into())),
+                Some(Fault::Eof) => Poll::Ready(Ok(())),
+                None => Pin::new(&mut self.io).poll_read(cx, buf),
+            }
+        }
+    }
+
+    impl AsyncWrite for MockTransport {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize, Error>> {
+            match self.take_fault() {
+                Some(Fault::Error(kind)) => Poll::Ready(Err(kind.into())),
+                Some(Fault::Eof) => Poll::Ready(Ok(0)),
+                None => Pin::new(&mut self.io).poll_write(cx, buf),
+            }
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Pin::new(&mut self.io).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Error>> {
+            Pin::new(&mut self.io).poll_shutdown(cx)
+        }
+    }
+
+    impl Connector for MockConnector {
+        type Target = &'static str;
+        type Transport = MockTransport;
+        type Future = Pin<Box<dyn Future<Output = Result<MockTransport, Error>> + Send>>;
+        type Addr = &'static str;
+
+        fn connect(&mut self, target: &Self::Target) -> Self::Future {
+            self.shared.lock().unwrap().attempts.push(target);
+            let shared = self.shared.clone();
+            let target = *target;
+            // peer is taken when connect is polled, test can add it after reset started connect
+            Box::pin(async move {
+                let mut shared = shared.lock().unwrap();
+                let transport = shared.peers.get_mut(target).and_then(VecDeque::pop_front);
+                transport.ok_or_else(|| ErrorKind::ConnectionRefused.into())
+            })
+        }
+
+        fn addrs(&self, transport: &MockTransport) -> Result<(&'static str, &'static str), Error> {
+            Ok(("local", transport.target))
+        }
+
+        fn unacked_bytes(&self, transport: &MockTransport) -> Option<usize> {
+            *transport.unacked.lock().unwrap()
+        }
+    }
+}
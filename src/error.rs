@@ -0,0 +1,211 @@
+//! [RetryingError] describing why [RetryingStream](crate::RetryingStream) operation failed.
+
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+
+use tokio::io::{Error, ErrorKind};
+
+/// What went wrong, see [RetryingError].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorCategory {
+    /// Connect attempt, configuration or handshake failed. Stream will reconnect.
+    Connect,
+    /// Established connection broke. Stream will reconnect.
+    Disconnected,
+    /// Operation needs connected transport and there is none at the moment.
+    NotConnected,
+    /// Stream was shut down.
+    Closed,
+    /// Error classified as [Fatal](crate::ErrorAction::Fatal) closed the stream.
+    Fatal,
+    /// Reconnect limit was reached, see [revive](crate::RetryingStream::revive).
+    GaveUp,
+    /// Write queue is full, see [QueueOverflow::Error](crate::queue::QueueOverflow::Error).
+    QueueFull,
+}
+
+/// Error returned by [RetryingStream](crate::RetryingStream).
+///
+/// Streams implement tokio traits, so it is returned wrapped in [io::Error](std::io::Error) with
+/// kind of the underlying error. Get it back with [from_io](RetryingError::from_io). Errors
+/// returned by transport that didn't reset the connection are not wrapped.
+#[derive(Debug, Clone)]
+pub struct RetryingError {
+    category: ErrorCategory,
+    attempt: u32,
+    target: String,
+    source: Option<Arc<Error>>,
+}
+
+impl RetryingError {
+    pub(crate) fn new(
+        category: ErrorCategory,
+        attempt: u32,
+        target: String,
+        source: Option<Arc<Error>>,
+    ) -> Self {
+        Self {
+            category,
+            attempt,
+            target,
+            source,
+        }
+    }
+
+    /// Return [RetryingError] wrapped in `err`, if there is one.
+    pub fn from_io(err: &Error) -> Option<&RetryingError> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        self.category
+    }
+
+    /// Number of consecutive reconnects when error happened. For [ErrorCategory::GaveUp] number
+    /// of failed connects.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// [Display](fmt::Display) of target in use when error happened.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Underlying error, if any.
+    pub fn io_error(&self) -> Option<&Error> {
+        self.source.as_deref()
+    }
+
+    /// OS error code of underlying error.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.source.as_ref()?.raw_os_error()
+    }
+
+    /// Kind of [io::Error](std::io::Error) this error is wrapped in.
+    pub fn kind(&self) -> ErrorKind {
+        match (&self.category, &self.source) {
+            (ErrorCategory::NotConnected | ErrorCategory::GaveUp, _) => ErrorKind::NotConnected,
+            (ErrorCategory::Closed, _) => ErrorKind::BrokenPipe,
+            (ErrorCategory::QueueFull, _) => ErrorKind::WouldBlock,
+            (_, Some(source)) => source.kind(),
+            (_, None) => ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for RetryingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.category {
+            ErrorCategory::Connect => write!(
+                f,
+                "connect to {} failed (attempt {})",
+                self.target, self.attempt
+            )?,
+            ErrorCategory::Disconnected => write!(f, "connection to {} broke", self.target)?,
+            ErrorCategory::NotConnected => write!(f, "not connected to {}", self.target)?,
+            ErrorCategory::Closed => f.write_str("RetryingStream was shut down")?,
+            ErrorCategory::Fatal => write!(f, "fatal error on connection to {}", self.target)?,
+            ErrorCategory::GaveUp => write!(
+                f,
+                "gave up reconnecting to {} after {} failed attempts",
+                self.target, self.attempt
+            )?,
+            ErrorCategory::QueueFull => {
+                write!(f, "write queue is full while connecting to {}", self.target)?
+            }
+        }
+        match &self.source {
+            Some(source) => write!(f, ": {}", source),
+            None => Ok(()),
+        }
+    }
+}
+
+impl error::Error for RetryingError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.source {
+            Some(source) => Some(&**source),
+            None => None,
+        }
+    }
+}
+
+// Stream reconnects after these errors, layers above it continue on new connection
+pub(crate) fn is_reconnecting(err: &Error) -> bool {
+    RetryingError::from_io(err).is_some_and(|err| {
+        matches!(
+            err.category(),
+            ErrorCategory::Connect | ErrorCategory::Disconnected | ErrorCategory::NotConnected
+        )
+    })
+}
+
+impl From<RetryingError> for Error {
+    fn from(err: RetryingError) -> Self {
+        Error::new(err.kind(), err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrap(category: ErrorCategory, source: Option<Error>) -> Error {
+        RetryingError::new(category, 2, "localhost:80".to_owned(), source.map(Arc::new)).into()
+    }
+
+    #[test]
+    fn kind_follows_category() {
+        let reset = || Some(ErrorKind::ConnectionReset.into());
+        let cases = [
+            (ErrorCategory::Connect, reset(), ErrorKind::ConnectionReset),
+            (
+                ErrorCategory::Disconnected,
+                reset(),
+                ErrorKind::ConnectionReset,
+            ),
+            (ErrorCategory::Fatal, reset(), ErrorKind::ConnectionReset),
+            (ErrorCategory::Connect, None, ErrorKind::Other),
+            (ErrorCategory::NotConnected, None, ErrorKind::NotConnected),
+            (ErrorCategory::GaveUp, reset(), ErrorKind::NotConnected),
+            (ErrorCategory::Closed, None, ErrorKind::BrokenPipe),
+            (ErrorCategory::QueueFull, None, ErrorKind::WouldBlock),
+        ];
+        for (category, source, kind) in cases {
+            let err = wrap(category, source);
+            assert_eq!(err.kind(), kind, "{:?}", category);
+            let retrying = RetryingError::from_io(&err).unwrap();
+            assert_eq!(retrying.category(), category);
+            assert_eq!(retrying.kind(), kind);
+            assert_eq!(retrying.attempt(), 2);
+            assert_eq!(retrying.target(), "localhost:80");
+        }
+    }
+
+    #[test]
+    fn source_is_kept() {
+        let err = wrap(
+            ErrorCategory::GaveUp,
+            Some(Error::new(ErrorKind::TimedOut, "no connection")),
+        );
+        let retrying = RetryingError::from_io(&err).unwrap();
+        assert_eq!(retrying.io_error().unwrap().kind(), ErrorKind::TimedOut);
+        assert!(error::Error::source(retrying).is_some());
+        assert_eq!(
+            retrying.to_string(),
+            "gave up reconnecting to localhost:80 after 2 failed attempts: no connection"
+        );
+    }
+
+    #[test]
+    fn other_errors_are_not_retrying() {
+        assert!(RetryingError::from_io(&ErrorKind::ConnectionReset.into()).is_none());
+        assert!(RetryingError::from_io(&Error::other("wrapped")).is_none());
+        assert!(!is_reconnecting(&ErrorKind::ConnectionReset.into()));
+        assert!(is_reconnecting(&wrap(ErrorCategory::Connect, None)));
+        assert!(!is_reconnecting(&wrap(ErrorCategory::Closed, None)));
+    }
+}
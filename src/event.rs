@@ -0,0 +1,98 @@
+//! Connection lifecycle events emitted by [RetryingStream](crate::RetryingStream).
+//!
+//! Register a callback with [on_event](crate::RetryingStream::on_event) or get a
+//! [Stream](futures::Stream) of events with [subscribe](crate::RetryingStream::subscribe).
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use tokio::io::Error;
+
+/// State transition of [RetryingStream](crate::RetryingStream).
+///
+/// `T` is [Connector::Target](crate::Connector::Target) and `A` is
+/// [Connector::Addr](crate::Connector::Addr).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Event<T, A> {
+    /// Connect attempt started.
+    ///
+    /// `attempt` is 0 for first connect and then number of consecutive reconnects since
+    /// connection was last stable.
+    Connecting { attempt: u32, addr: T },
+    /// Connection established and configured.
+    Connected { local: A, peer: A },
+    /// Connect attempt failed.
+    ConnectFailed { attempt: u32, error: Arc<Error> },
+    /// Established connection was dropped because of `error`.
+    Disconnected { error: Arc<Error> },
+    /// Waiting `delay` before next connect attempt.
+    BackingOff { delay: Duration },
+    /// Stream was shut down and will not reconnect.
+    Closed,
+    /// Reconnect limit was reached after `attempts` consecutive failed connects. Stream will not
+    /// reconnect until [revive](crate::RetryingStream::revive) is called.
+    GaveUp { attempts: u32, error: Arc<Error> },
+}
+
+/// [Stream](futures::Stream) of events returned by [subscribe](crate::RetryingStream::subscribe).
+pub type EventStream<T, A> = mpsc::UnboundedReceiver<Event<T, A>>;
+
+type Callback<T, A> = Box<dyn FnMut(&Event<T, A>) + Send>;
+
+// Everybody interested in events
+pub(crate) struct Observers<T, A> {
+    callbacks: Vec<Callback<T, A>>,
+    senders: Vec<mpsc::UnboundedSender<Event<T, A>>>,
+}
+
+impl<T: Clone, A: Clone> Observers<T, A> {
+    pub(crate) fn new() -> Self {
+        Self {
+            callbacks: Vec::new(),
+            senders: Vec::new(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.callbacks.is_empty() && self.senders.is_empty()
+    }
+
+    pub(crate) fn add_callback(
+        &mut self,
+        mut callback: Callback<T, A>,
+        current: Option<Event<T, A>>,
+    ) {
+        if let Some(event) = current {
+            callback(&event);
+        }
+        self.callbacks.push(callback);
+    }
+
+    pub(crate) fn subscribe(&mut self, current: Option<Event<T, A>>) -> EventStream<T, A> {
+        let (tx, rx) = mpsc::unbounded();
+        if let Some(event) = current {
+            let _ = tx.unbounded_send(event);
+        }
+        self.senders.push(tx);
+        rx
+    }
+
+    pub(crate) fn emit(&mut self, event: Event<T, A>) {
+        for callback in &mut self.callbacks {
+            callback(&event);
+        }
+        // drop senders of streams that were dropped
+        self.senders
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+// io::Error is not Clone. Keep kind, OS error code and message.
+pub(crate) fn clone_error(err: &Error) -> Error {
+    match err.raw_os_error() {
+        Some(code) => Error::from_raw_os_error(code),
+        None => Error::new(err.kind(), err.to_string()),
+    }
+}
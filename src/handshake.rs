@@ -0,0 +1,31 @@
+//! Exchange run on every new connection before it is handed to the application.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use tokio::io::Error;
+
+/// Future returned by [Handshake].
+pub type HandshakeFuture<T> = Pin<Box<dyn Future<Output = Result<T, Error>> + Send>>;
+
+/// Login/authentication exchange run on every freshly connected transport.
+///
+/// Handshake gets exclusive access to the transport and must give it back. Until it completes
+/// [RetryingStream](crate::RetryingStream) is not connected and `poll_read`/`poll_write` return
+/// `Pending`. Error is treated like connect error and goes through reset.
+///
+/// Implemented for closures, e.g.
+/// `|mut ts: TcpStream| async move { ts.write_all(b"LOGIN\n").await?; Ok(ts) }`.
+pub trait Handshake<T>: Send {
+    fn handshake(&mut self, transport: T) -> HandshakeFuture<T>;
+}
+
+impl<T, F, Fut> Handshake<T> for F
+where
+    F: FnMut(T) -> Fut + Send,
+    Fut: Future<Output = Result<T, Error>> + Send + 'static,
+{
+    fn handshake(&mut self, transport: T) -> HandshakeFuture<T> {
+        Box::pin(self(transport))
+    }
+}
@@ -1,306 +1,217 @@
-//! This crate wraps [TcpStream] and [ConnectFuture] into [RetryingTcpStream].
+//! This crate wraps [TcpStream] and its connect future into [RetryingTcpStream].
 //!
 //! When you work with connection that are expected to sometimes broke you may want add auto reconnect after
 //! error is detected. [RetryingTcpStream] makes pollable after returning error.
-//! It mean any time, any method that name start with `poll` return Error - the inner state will reset.
+//! It mean any time, any method that name start with `poll` return Error - the inner state will reset
+//! (unless [ErrorClassifier] says otherwise).
 //!
-//! When you think about [RetryingTcpStream] you should think about mix [ConnectFuture] and [TcpStream].
+//! When you think about [RetryingTcpStream] you should think about mix of connect future and [TcpStream].
 //! When you call `poll_read()` or `poll_write()` on [RetryingTcpStream]
-//! 1. If it is in [ConnectFuture] state -> will go to [TcpStream] state and go to 2.
+//! 1. If it is in ConnectFuture state -> will go to [TcpStream] state and go to 2.
 //! 2. If it is in [TcpStream] state -> will call requested method retrurning result:
-//!   - `Ok(_)` -> Normal poll result
-//!   - `Err(_)` -> Internal state is reset to [ConnectFuture] state. Next poll*() method will try connect.
+//!    - `Ready(Ok(_))` -> Normal poll result
+//!    - `Ready(Err(_))` -> Internal state is reset to ConnectFuture state. Next poll*() method will try connect.
+//!
+//! [RetryingTcpStream] implements tokio 1 [AsyncRead] and [AsyncWrite] so it can be used with
+//! `AsyncReadExt`/`AsyncWriteExt` from async/await code.
 //!
 //! [RetryingTcpStream] is design to work with [futures-retry]. It's up to you with error are temporary and can be repair by reconnecting.
 //!
+//! # Builder
+//! [builder](RetryingTcpStream::builder) gathers targets, socket options, timeouts, backoff and
+//! hooks in one place and validates them before creating the stream. `connect_*` constructors are
+//! shortcuts for common cases.
+//!
+//! # Errors
+//! Not every I/O error means broken connection. [ErrorClassifier] set with
+//! [set_error_classifier](RetryingStream::set_error_classifier) decides if error resets the
+//! connection, is only returned to the caller, or closes the stream for good. Default one
+//! resets on OS errors other than temporary ones like `EINTR`.
+//!
+//! Errors that reset or close the stream are returned as [RetryingError] wrapped in `io::Error`
+//! of the same kind. It tells what failed (connect, established connection, stream is shut down,
+//! ...), attempt number and target. Get it with [RetryingError::from_io].
+//!
+//! Peer closing the connection gracefully is not an error: read returns `Ok(0)`. Set
+//! [EofPolicy] with [set_eof_policy](RetryingStream::set_eof_policy) to reconnect in that case.
+//!
+//! # Shutdown
+//! [poll_shutdown](tokio::io::AsyncWrite::poll_shutdown) moves stream to closed state in every
+//! state. Pending connect is cancelled, and closed stream never reconnects: writes return
+//! `BrokenPipe` error, reads return rest of data from shut down connection.
+//!
+//! # Backoff
+//! By default new connection is started immediately after reset. To not hammer dead server set a
+//! [ReconnectPolicy] with [set_reconnect_policy](RetryingTcpStream::set_reconnect_policy).
+//! Between attempts stream wait in backoff state and every `poll*()` method return `Pending`.
+//! Once connection stays up for [stable period](RetryingTcpStream::set_stable_period) the policy is
+//! reset and next reconnect will be treated as first one.
+//!
+//! Stream retries forever unless [set_max_attempts](RetryingStream::set_max_attempts) or
+//! [set_give_up_after](RetryingStream::set_give_up_after) is used. Once limit is reached every
+//! `poll*()` method return [RetryingError] of [GaveUp](ErrorCategory::GaveUp) category until
+//! [revive](RetryingStream::revive) is called.
+//!
+//! # Handshake
+//! Protocols that need login or authentication after connect can set a [Handshake] with
+//! [set_handshake](RetryingStream::set_handshake). It runs on every new connection and has to
+//! finish before `poll_read()`/`poll_write()` see the stream as connected, so silent reconnect never
+//! hands out unauthenticated socket. Handshake error is treated like connect error.
+//!
+//! # Replay
+//! Bytes accepted by `poll_write()` but still in kernel send queue are lost with the connection.
+//! Set [ReplayPolicy] with [set_replay_policy](RetryingStream::set_replay_policy) to retain
+//! recently written bytes and write unacknowledged ones to the new connection before new data.
+//! On Linux their number is read with `SIOCOUTQ`; elsewhere all retained bytes are written again.
+//! When buffer is full it either drops oldest bytes or blocks writes until peer acknowledges
+//! enough of them, see [Overflow].
+//!
+//! # Write queue
+//! While stream connects `poll_write()` returns `Pending`. Set [QueuePolicy] with
+//! [set_write_queue](RetryingStream::set_write_queue) to accept writes into bounded queue
+//! instead and write it to the new connection once established. When queue is full writes block,
+//! drop oldest or newest bytes or fail, see [QueueOverflow].
+//! [queued_bytes](RetryingStream::queued_bytes) and [Stats] show queue depth. Shutdown of
+//! connected stream writes the queue first; when that fails the stream is closed anyway and the
+//! queue dropped.
+//!
+//! For outages longer than memory allows use [Spool] with
+//! [set_spool](RetryingStream::set_spool): queue is kept in append-only file, flushed to disk
+//! according to [SyncPolicy], and data left there when process exits is written after next
+//! start.
+//!
+//! # Sessions
+//! Replay can't help with bytes peer received but never read, nor with data in the other
+//! direction. [Session] layered on the stream numbers all data, keeps it until peer
+//! acknowledges it and after reconnect runs resume handshake, so both sides continue exactly
+//! where they left off. Peer has to speak the session protocol described there;
+//! [SessionListener] implements the server side, keeping sessions of disconnected clients until
+//! they reconnect or expire.
+//!
+//! # Messages
+//! Connection can break in the middle of a write, then peer gets truncated frame followed by the
+//! rest on the next connection. [MessageSink] implements [Sink](futures::Sink) of [Bytes] and
+//! writes every message whole to one connection: message that wasn't written and flushed before
+//! connection broke is written again from the start, as are messages peer didn't acknowledge.
+//!
+//! # Events
+//! Every state transition emits an [Event]: `Connecting`, `Connected`, `ConnectFailed`,
+//! `Disconnected`, `BackingOff`, `Closed` and `GaveUp`. Receive them with callback registered by
+//! [on_event](RetryingStream::on_event) or as [Stream](futures::Stream) returned from
+//! [subscribe](RetryingStream::subscribe), e.g. to resend application state after reconnect.
+//!
+//! [stats](RetryingStream::stats) returns [Stats] snapshot with connect attempts, failures by error
+//! kind, bytes transferred and age of current connection, e.g. to report flakiness of upstream.
+//! Enable `serde` feature to serialize it.
+//!
+//! With `metrics` feature stream also reports through [metrics](https://docs.rs/metrics) facade:
+//! counters `retrying_stream_connect_attempts_total`, `retrying_stream_connects_total`,
+//! `retrying_stream_connect_failures_total`, `retrying_stream_disconnects_total`,
+//! `retrying_stream_bytes_read_total` and `retrying_stream_bytes_written_total`, histogram
+//! `retrying_stream_connect_duration_seconds` and gauge `retrying_stream_connected`. They are
+//! labelled with `stream` [name](RetryingStream::set_name) and `target`; failures also with
+//! error `kind`. Recording starts with first connect made by the stream, or right away for stream
+//! created from connected transport.
+//!
+//! With `tracing` feature stream opens `retrying_stream` span and, for every connect attempt,
+//! child `connection` span with target, attempt, local and peer address and bytes transferred.
+//! Stream created from connected transport opens both spans right away.
+//! Connect failures and disconnects are logged with error and its kind, socket options with
+//! applied [TcpStreamSettings].
+//!
+//! # Connect timeout
+//! Peer that drops SYN packets would keep stream in ConnectFuture state for minutes.
+//! Set [connect_timeout](TcpStreamSettings::connect_timeout) to abort such attempt with `TimedOut`
+//! error that goes through normal reset path. With
+//! [connect_timeout_max](TcpStreamSettings::connect_timeout_max) the timeout doubles after each
+//! failed attempt.
+//!
+//! # Socket options
+//! [TcpStreamSettings] cover buffer sizes, TTL, linger, TOS, keepalive interval and probe count,
+//! `TCP_USER_TIMEOUT`, `SO_MARK`, `TCP_QUICKACK` and local bind address. Stream creates every
+//! socket itself and applies them before connect, so reconnected socket is configured the same as
+//! the first one. Setters like [set_keepalive](RetryingTcpStream::set_keepalive) record new value
+//! in the settings and apply it to current connection if there is one;
+//! [live_tcp_settings](RetryingTcpStream::live_tcp_settings) reads values back from the socket.
+//!
+//! # Host names
+//! [connect_host](RetryingTcpStream::connect_host) accepts `host:port`. Name is resolved again
+//! on every reconnect with pluggable [Resolver], so stream follows service that moved to new IP.
+//! Wrap resolver in [CachingResolver] to not query DNS on every attempt.
+//!
+//! # Many targets
+//! [connect_with_failover](RetryingTcpStream::connect_with_failover) takes list of targets.
+//! On reset [Strategy] picks the next one: ordered failover, round-robin, random or weighted.
+//! With [set_failback_after](RetryingStream::set_failback_after) stream returns to the primary
+//! target after it was on backup for a while, once new connection to primary succeeded.
+//! [target](RetryingStream::target) return the one in use.
+//!
+//! # Other transports
+//! [RetryingTcpStream] is an alias for [RetryingStream] using [TcpConnector]. Implement
+//! [Connector] to get the same reconnect logic for Unix sockets, TLS or test doubles.
+//!
+//! # tokio 0.1
+//! Implementation for tokio 0.1 and futures 0.1 is available in `tokio01` module with `tokio01` feature.
+//!
 //!
 //! [RetryingTcpStream]: RetryingTcpStream
-//! [futures-retry]: https://docs.rs/futures-retry/0.3
-//! [ConnectFuture]: tokio::net::tcp::ConnectFuture
+//! [RetryingStream]: RetryingStream
+//! [TcpConnector]: TcpConnector
+//! [Connector]: Connector
+//! [ReconnectPolicy]: policy::ReconnectPolicy
+//! [Resolver]: resolve::Resolver
+//! [CachingResolver]: resolve::CachingResolver
+//! [ReplayPolicy]: replay::ReplayPolicy
+//! [Overflow]: replay::Overflow
+//! [QueuePolicy]: queue::QueuePolicy
+//! [QueueOverflow]: queue::QueueOverflow
+//! [Spool]: spool::Spool
+//! [SyncPolicy]: spool::SyncPolicy
+//! [Bytes]: bytes::Bytes
+//! [futures-retry]: https://docs.rs/futures-retry/0.6
 //! [TcpStream]: tokio::net::TcpStream
+//! [AsyncRead]: tokio::io::AsyncRead
+//! [AsyncWrite]: tokio::io::AsyncWrite
 
-use std::convert::TryFrom;
-use std::io::{Read, Write};
-use std::net::Shutdown;
 use std::time::Duration;
 
-use futures::try_ready;
-use log::debug;
-use mio;
-use tokio::io::{AsyncRead, AsyncWrite, Error};
-use tokio::prelude::{Async, Future, Poll};
-
-/// Holding settings [TcpStreamSettings]
-#[derive(Hash, PartialEq, Eq, Clone)]
-pub struct TcpStreamSettings {
-    pub nodelay: bool,
-    pub keepalive: Option<Duration>,
-}
-
-// Handle connection state
-enum ConnectionState {
-    ConnectFuture(tokio::net::tcp::ConnectFuture),
-    TcpStream(tokio::net::TcpStream),
-}
-
-/// Like TcpStream but pollable after Error.
-pub struct RetryingTcpStream {
-    addr: std::net::SocketAddr,
-    settings: TcpStreamSettings,
-    state: ConnectionState,
-}
-
-impl TryFrom<tokio::net::TcpStream> for RetryingTcpStream {
-    type Error = Error;
-    fn try_from(tcp_stream: tokio::net::TcpStream) -> Result<Self, Self::Error> {
-        let settings = TcpStreamSettings {
-            nodelay: tcp_stream.nodelay()?,
-            keepalive: tcp_stream.keepalive()?,
-        };
-
-        Ok(RetryingTcpStream {
-            addr: tcp_stream.peer_addr()?,
-            state: ConnectionState::TcpStream(tcp_stream),
-            settings,
-        })
-    }
-}
-
-/// Implement creators
-impl RetryingTcpStream {
-    pub fn connect_with_settings(addr: &std::net::SocketAddr, settings: TcpStreamSettings) -> Self {
-        Self {
-            addr: addr.clone(),
-            state: ConnectionState::ConnectFuture(tokio::net::TcpStream::connect(addr)),
-            settings,
-        }
-    }
-
-    pub fn from_std(
-        stream: std::net::TcpStream,
-        handle: &tokio::reactor::Handle,
-    ) -> Result<Self, Error> {
-        let tokio_tcp_stream = tokio::net::TcpStream::from_std(stream, handle)?;
-        Self::try_from(tokio_tcp_stream)
-    }
-}
-
-/// Reimplement of methods from [TcpStream]
-/// The main differences are:
-/// - `tokio::io::ErrorKind::NotConnected` is returned when inner state is in [ConnectFuture] and
-/// there is no other way to get response.
-/// - poll methods will try first go from [ConnectFuture] -> [TcpStream]
-///
-/// For more documentation go to [TcpStream] doc.
-///
-/// [TcpStream]:tokio::net::TcpStream
-/// [ConnectFuture]:tokio::net::tcp::ConnectFuture
-impl RetryingTcpStream {
-    pub fn poll_read_ready(&mut self, mask: mio::Ready) -> Result<Async<mio::Ready>, Error> {
-        let ts = try_ready!(self.poll_into_tcp_stream());
-        let res = ts.poll_read_ready(mask);
-        self.call_reset_if_io_is_closed2(res)
-    }
-
-    pub fn poll_write_ready(&mut self) -> Result<Async<mio::Ready>, Error> {
-        let ts = try_ready!(self.poll_into_tcp_stream());
-        let res = ts.poll_write_ready();
-        self.call_reset_if_io_is_closed2(res)
-    }
-
-    pub fn poll_peek(&mut self, buf: &mut [u8]) -> Result<Async<usize>, Error> {
-        let ts = try_ready!(self.poll_into_tcp_stream());
-        let res = ts.poll_peek(buf);
-        self.call_reset_if_io_is_closed2(res)
-    }
-
-    pub fn local_addr(&self) -> Result<std::net::SocketAddr, Error> {
-        self.ref_tcp_stream()?.local_addr()
-    }
-
-    pub fn peer_addr(&self) -> Result<std::net::SocketAddr, Error> {
-        match &self.state {
-            ConnectionState::ConnectFuture(_) => Ok(self.addr),
-            ConnectionState::TcpStream(ts) => {
-                let r = ts.peer_addr()?;
-                debug_assert_eq!(r, self.addr);
-                Ok(r)
-            }
-        }
-    }
-
-    pub fn nodelay(&self) -> Result<bool, Error> {
-        match self.ref_tcp_stream() {
-            Ok(ts) => {
-                let r = ts.nodelay()?;
-                debug_assert!(r, self.settings.nodelay);
-                Ok(r)
-            }
-            Err(_) => Ok(self.settings.nodelay),
-        }
-    }
-
-    pub fn set_nodelay(&mut self, nodelay: bool) -> Result<(), Error> {
-        match &self.state {
-            ConnectionState::ConnectFuture(_) => {
-                self.settings.nodelay = nodelay;
-                Ok(())
-            }
-            ConnectionState::TcpStream(ts) => match ts.set_nodelay(nodelay) {
-                Result::Ok(_) => {
-                    self.settings.nodelay = nodelay;
-                    Ok(())
-                }
-                Result::Err(err) => Err(err),
-            },
-        }
-    }
-
-    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
-        self.ref_tcp_stream()?.shutdown(how)
-    }
-
-    pub fn keepalive(&self) -> Result<Option<Duration>, Error> {
-        match self.ref_tcp_stream() {
-            Ok(ts) => {
-                let r = ts.keepalive()?;
-                debug_assert_eq!(r, self.settings.keepalive);
-                Ok(r)
-            }
-            Err(_) => Ok(self.settings.keepalive),
-        }
-    }
-
-    pub fn set_keepalive(&self, keepalive: Option<Duration>) -> Result<(), Error> {
-        self.ref_tcp_stream()?.set_keepalive(keepalive)
-    }
-}
-
-/// Implement additional methods
-impl RetryingTcpStream {
-    pub fn set_tcp_settings(&mut self, tcp_settings: TcpStreamSettings) -> Result<(), Error> {
-        self.set_nodelay(tcp_settings.nodelay)?;
-        self.set_keepalive(tcp_settings.keepalive)?;
-
-        self.settings = tcp_settings;
-        Ok(())
-    }
-
-    /// return true if RetryingTcpStream represent [TcpStream](tokio::net::TcpStream) at this
-    /// moment.
-    ///
-    /// This can change after calling any `poll*()` method when that function returned error.
-    pub fn is_in_tcp_state(&self) -> bool {
-        match self.state {
-            ConnectionState::TcpStream(_) => true,
-            ConnectionState::ConnectFuture(_) => false,
-        }
-    }
-
-    fn ref_tcp_stream(&self) -> Result<&tokio::net::TcpStream, Error> {
-        match &self.state {
-            ConnectionState::ConnectFuture(_) => {
-                Err(Error::from(tokio::io::ErrorKind::NotConnected))
-            }
-            ConnectionState::TcpStream(ts) => Ok(ts),
-        }
-    }
-
-    // Return NotReady until ConnectionState is diffrent than TcpStream
-    fn poll_into_tcp_stream(&mut self) -> Poll<&mut tokio::net::TcpStream, Error> {
-        match &mut self.state {
-            ConnectionState::ConnectFuture(cf) => {
-                let tcp_s = match cf.poll() {
-                    Ok(Async::Ready(tcp_s)) => tcp_s,
-                    Ok(Async::NotReady) => return Ok(Async::NotReady),
-                    Err(err) => {
-                        self.reset();
-                        return Err(err);
-                    }
-                };
-                self.state = ConnectionState::TcpStream(tcp_s);
-                self.set_tcp_settings(self.settings.clone())?;
-                debug!("RetryingTcpStream => change state ConnectFuture -> TcpStream")
-            }
-            ConnectionState::TcpStream(_) => (),
-        };
-
-        match self.state {
-            ConnectionState::ConnectFuture(_) => unreachable!(),
-            ConnectionState::TcpStream(ref mut ts) => Ok(Async::Ready(ts)),
-        }
-    }
-
-    fn reset(&mut self) {
-        debug!("RetryinTcpStream => reset was called!");
-        self.state = ConnectionState::ConnectFuture(tokio::net::TcpStream::connect(&self.addr))
-    }
-
-    fn call_reset_if_io_is_closed2<T>(&mut self, res: Result<T, Error>) -> Result<T, Error> {
-        use tokio::io::ErrorKind;
-        match res {
-            Ok(ok) => Ok(ok),
-            Err(err) => {
-                match err.kind() {
-                    ErrorKind::WouldBlock => (),
-                    _ => self.reset(),
-                };
-                Err(err)
-            }
-        }
-    }
-}
-
-impl Read for RetryingTcpStream {
-    /// # Note
-    /// This is Async version of Read. It will panic outside of tash
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let ts = self.poll_into_tcp_stream()?;
-        let r = match ts {
-            Async::Ready(ts) => ts.read(buf),
-            Async::NotReady => Err(std::io::ErrorKind::WouldBlock.into()),
-        };
-
-        self.call_reset_if_io_is_closed2(r)
-    }
-}
-
-impl Write for RetryingTcpStream {
-    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
-        let ts = self.poll_into_tcp_stream()?;
-        let r = match ts {
-            Async::Ready(ts) => ts.write(buf),
-            Async::NotReady => Err(std::io::ErrorKind::WouldBlock.into()),
-        };
-
-        self.call_reset_if_io_is_closed2(r)
-    }
-
-    fn flush(&mut self) -> Result<(), Error> {
-        let ts = self.poll_into_tcp_stream()?;
-        let r = match ts {
-            Async::Ready(ts) => ts.flush(),
-            Async::NotReady => Err(std::io::ErrorKind::WouldBlock.into()),
-        };
-
-        self.call_reset_if_io_is_closed2(r)
-    }
-}
-
-// Logic is implemented inside Read and Write trait becouse we can't overwrite AsyncRead and
-// AsyncWrite for Box<RetryingTcpStream>
-// source: https://docs.rs/tokio-io/0.1.12/src/tokio_io/async_write.rs.html#149
-impl AsyncRead for RetryingTcpStream {}
-
-impl AsyncWrite for RetryingTcpStream {
-    fn shutdown(&mut self) -> Poll<(), Error> {
-        match &mut self.state {
-            ConnectionState::ConnectFuture(_cf) => {
-                // there is a chance when we call poll conection will resolve to TcpStream
-                // we probably need add a Shutdowned state.
-                unimplemented!();
-            }
-            ConnectionState::TcpStream(ts) => ts.shutdown(),
-        }
-    }
-}
+pub mod builder;
+mod channel;
+pub mod classify;
+pub mod connector;
+pub mod error;
+pub mod event;
+pub mod handshake;
+pub mod listener;
+pub mod message;
+pub mod policy;
+pub mod queue;
+pub mod replay;
+pub mod resolve;
+pub mod session;
+mod sockopt;
+pub mod spool;
+pub mod stats;
+mod stream;
+pub mod targets;
+mod tcp;
+mod telemetry;
+#[cfg(feature = "tokio01")]
+pub mod tokio01;
+
+pub use builder::{BuildError, TcpStreamBuilder};
+pub use classify::{EofPolicy, ErrorAction, ErrorClassifier};
+pub use connector::Connector;
+pub use error::{ErrorCategory, RetryingError};
+pub use event::Event;
+pub use handshake::Handshake;
+pub use listener::{SessionListener, SessionStream};
+pub use message::{MessageSink, TcpMessageSink};
+pub use session::{Session, TcpSession};
+pub use stats::Stats;
+pub use stream::{RetryingStream, StreamEvent};
+pub use targets::Strategy;
+pub use tcp::{RetryingTcpStream, TcpConnector, TcpEvent, TcpStreamSettings, TcpTarget};
+
+/// Default time connection has to stay up before reconnect policy is reset.
+pub const DEFAULT_STABLE_PERIOD: Duration = Duration::from_secs(30);
@@ -0,0 +1,429 @@
+//! [SessionListener] accepting [Session](crate::Session) clients.
+
+use std::collections::HashMap;
+use std::future::{self, Future};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::task::AtomicWaker;
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, Error, ErrorKind, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::time::Sleep;
+
+use crate::channel::{
+    Channel, Endpoint, Hello, Wakers, DEFAULT_MAX_UNACKED, HELLO_LEN, HELLO_TIMEOUT,
+};
+
+/// How long session waits for client to reconnect, unless changed with
+/// [set_session_timeout](SessionListener::set_session_timeout).
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+type HelloFuture =
+    Pin<Box<dyn Future<Output = Result<(TcpStream, SocketAddr, Hello), Error>> + Send>>;
+
+// Session state shared by listener and SessionStream
+struct Slot {
+    // reconnected client with position it sent in HELLO, waiting to be picked up by the stream
+    attached: Option<(TcpStream, u64)>,
+    disconnected_since: Option<Instant>,
+    expired: bool,
+    dropped: bool,
+    // wakes tasks using the stream
+    waker: Waker,
+}
+
+/// Server side of [Session](crate::Session) protocol, wrapping [TcpListener].
+///
+/// [accept](SessionListener::accept) returns [SessionStream] for every new session. Client that
+/// reconnects is recognized by session id and its connection is handed to existing stream,
+/// which then resends data client didn't receive; such connections are not returned. Keep
+/// calling `accept`, reconnects are handled only while it is polled.
+///
+/// Session without connection for [session timeout](SessionListener::set_session_timeout)
+/// expires: its stream fails with `ConnectionAborted` error and client reconnecting later is
+/// told the session is unknown. Connection that looks alive, e.g. half-open one, doesn't count
+/// as disconnected.
+pub struct SessionListener {
+    listener: TcpListener,
+    // connections waiting for HELLO
+    hellos: FuturesUnordered<HelloFuture>,
+    sessions: HashMap<u64, Arc<Mutex<Slot>>>,
+    session_timeout: Duration,
+    max_unacked: usize,
+    // wake up to expire sessions
+    expiry_timer: Option<Pin<Box<Sleep>>>,
+    // woken by streams that lost connection or were dropped
+    waker: Arc<AtomicWaker>,
+}
+
+impl SessionListener {
+    /// Create listener bound to `addr`.
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+        Ok(Self::from_listener(TcpListener::bind(addr).await?))
+    }
+
+    pub fn from_listener(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            hellos: FuturesUnordered::new(),
+            sessions: HashMap::new(),
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+            max_unacked: DEFAULT_MAX_UNACKED,
+            expiry_timer: None,
+            waker: Arc::new(AtomicWaker::new()),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.listener.local_addr()
+    }
+
+    /// Set how long session waits for client to reconnect. Default is
+    /// [DEFAULT_SESSION_TIMEOUT].
+    pub fn set_session_timeout(&mut self, session_timeout: Duration) {
+        self.session_timeout = session_timeout;
+        self.expiry_timer = None;
+    }
+
+    /// See [Session::set_max_unacked](crate::Session::set_max_unacked). Used for sessions
+    /// accepted later.
+    pub fn set_max_unacked(&mut self, max_unacked: usize) {
+        self.max_unacked = max_unacked;
+    }
+
+    /// Number of sessions that didn't expire and whose stream wasn't dropped.
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Wait for new session. Return its stream and address of client.
+    pub async fn accept(&mut self) -> Result<(SessionStream, SocketAddr), Error> {
+        future::poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    /// Poll for new session, see [accept](SessionListener::accept).
+    pub fn poll_accept(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(SessionStream, SocketAddr), Error>> {
+        self.waker.register(cx.waker());
+        self.expire(cx);
+        loop {
+            if let Poll::Ready(res) = self.listener.poll_accept(cx) {
+                let (ts, addr) = res?;
+                self.hellos.push(Box::pin(read_hello(ts, addr)));
+                continue;
+            }
+            match ready!(self.hellos.poll_next_unpin(cx)) {
+                Some(Ok((ts, addr, hello))) => {
+                    if let Some(stream) = self.on_hello(ts, addr, hello) {
+                        return Poll::Ready(Ok((stream, addr)));
+                    }
+                }
+                Some(Err(err)) => debug!("SessionListener => HELLO failed: {}", err),
+                None => return Poll::Pending,
+            }
+        }
+    }
+
+    // Open new session or attach connection to existing one
+    fn on_hello(&mut self, ts: TcpStream, addr: SocketAddr, hello: Hello) -> Option<SessionStream> {
+        if hello.session_id == 0 {
+            let id = loop {
+                let id = rand::random();
+                if id != 0 && !self.sessions.contains_key(&id) {
+                    break id;
+                }
+            };
+            debug!("SessionListener => new session {} from {}", id, addr);
+            let stream = SessionStream::new(
+                id,
+                (ts, hello.received),
+                self.max_unacked,
+                self.waker.clone(),
+            );
+            self.sessions.insert(id, stream.slot.clone());
+            return Some(stream);
+        }
+        match self.sessions.get(&hello.session_id) {
+            Some(slot) => {
+                debug!(
+                    "SessionListener => session {} resumed from {}",
+                    hello.session_id, addr
+                );
+                let mut slot = slot.lock().unwrap();
+                slot.attached = Some((ts, hello.received));
+                slot.disconnected_since = None;
+                slot.waker.wake_by_ref();
+            }
+            None => {
+                debug!(
+                    "SessionListener => unknown session {} from {}",
+                    hello.session_id, addr
+                );
+                // reply fits into send buffer of fresh connection
+                let reply = Hello {
+                    session_id: 0,
+                    received: 0,
+                };
+                let _ = ts.try_write(&reply.encode());
+            }
+        }
+        None
+    }
+
+    // Forget dropped and expired sessions, wake up when next one expires
+    fn expire(&mut self, cx: &mut Context<'_>) {
+        let now = Instant::now();
+        let mut next = None;
+        let session_timeout = self.session_timeout;
+        self.sessions.retain(|id, slot| {
+            let mut slot = slot.lock().unwrap();
+            if slot.dropped {
+                return false;
+            }
+            let deadline = match slot.disconnected_since {
+                Some(since) if slot.attached.is_none() => since + session_timeout,
+                _ => return true,
+            };
+            if deadline <= now {
+                debug!("SessionListener => session {} expired", id);
+                slot.expired = true;
+                slot.waker.wake_by_ref();
+                return false;
+            }
+            next = Some(next.map_or(deadline, |next: Instant| next.min(deadline)));
+            true
+        });
+        self.expiry_timer =
+            next.map(|deadline| Box::pin(tokio::time::sleep_until(deadline.into())));
+        if let Some(timer) = &mut self.expiry_timer {
+            let _ = timer.as_mut().poll(cx);
+        }
+    }
+}
+
+// Read HELLO from new connection
+async fn read_hello(
+    mut ts: TcpStream,
+    addr: SocketAddr,
+) -> Result<(TcpStream, SocketAddr, Hello), Error> {
+    let mut hello = [0; HELLO_LEN];
+    match tokio::time::timeout(HELLO_TIMEOUT, ts.read_exact(&mut hello)).await {
+        Ok(res) => res?,
+        Err(_) => return Err(Error::new(ErrorKind::TimedOut, "HELLO timed out")),
+    };
+    Ok((ts, addr, Hello::decode(&hello)?))
+}
+
+/// Server end of [Session](crate::Session), returned by [SessionListener].
+///
+/// Behaves like client session: data survives reconnects, `poll_shutdown` sends end of data and
+/// completes when client read all of it. While client is disconnected `poll_*()` methods return
+/// `Pending`, until it reconnects or session expires.
+pub struct SessionStream {
+    id: u64,
+    slot: Arc<Mutex<Slot>>,
+    conn: Option<TcpStream>,
+    channel: Channel,
+    // session can't continue
+    failed: Option<Error>,
+    wakers: Arc<Wakers>,
+    // wakes all of `wakers`, used for every poll of connection
+    waker: Waker,
+    listener: Arc<AtomicWaker>,
+}
+
+impl SessionStream {
+    fn new(
+        id: u64,
+        attached: (TcpStream, u64),
+        max_unacked: usize,
+        listener: Arc<AtomicWaker>,
+    ) -> Self {
+        let wakers = Arc::new(Wakers::default());
+        let waker = Waker::from(wakers.clone());
+        let slot = Slot {
+            attached: Some(attached),
+            disconnected_since: None,
+            expired: false,
+            dropped: false,
+            waker: waker.clone(),
+        };
+        Self {
+            id,
+            slot: Arc::new(Mutex::new(slot)),
+            conn: None,
+            channel: Channel::new(max_unacked),
+            failed: None,
+            wakers,
+            waker,
+            listener,
+        }
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.id
+    }
+
+    /// See [Session::set_max_unacked](crate::Session::set_max_unacked).
+    pub fn set_max_unacked(&mut self, max_unacked: usize) {
+        self.channel.set_max_unacked(max_unacked);
+    }
+
+    /// Return true if client is connected at this moment.
+    pub fn is_connected(&self) -> bool {
+        self.conn.is_some() || self.slot.lock().unwrap().attached.is_some()
+    }
+
+    /// Address of connected client.
+    pub fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        match &self.conn {
+            Some(conn) => conn.peer_addr(),
+            None => Err(Error::new(
+                ErrorKind::NotConnected,
+                "client is not connected",
+            )),
+        }
+    }
+
+    // Connection broke, wait for client to reconnect
+    fn disconnect(&mut self, err: &Error) {
+        debug!("SessionStream => session {} disconnected: {}", self.id, err);
+        self.conn = None;
+        self.slot.lock().unwrap().disconnected_since = Some(Instant::now());
+        self.listener.wake();
+    }
+}
+
+impl Endpoint for SessionStream {
+    fn channel(&mut self) -> &mut Channel {
+        &mut self.channel
+    }
+
+    fn wakers(&self) -> &Wakers {
+        &self.wakers
+    }
+
+    fn failed(&mut self) -> &mut Option<Error> {
+        &mut self.failed
+    }
+
+    // Switch to connection client reconnected with
+    fn resume(&mut self) -> Result<bool, Error> {
+        let mut slot = self.slot.lock().unwrap();
+        if slot.expired {
+            Err(Error::new(ErrorKind::ConnectionAborted, "session expired"))
+        } else if let Some((conn, peer_received)) = slot.attached.take() {
+            self.conn = Some(conn);
+            let res = self.channel.resume(peer_received);
+            self.channel.send_hello(self.id);
+            res.map(|()| true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn poll_send(&mut self) -> Poll<Result<(), Error>> {
+        let waker = self.waker.clone();
+        let cx = &mut Context::from_waker(&waker);
+        loop {
+            self.check()?;
+            if self.channel.is_sent() {
+                return Poll::Ready(Ok(()));
+            }
+            let conn = match &mut self.conn {
+                Some(conn) => conn,
+                None => return Poll::Pending,
+            };
+            let output = self.channel.output();
+            match ready!(Pin::new(conn).poll_write(cx, output)) {
+                Ok(0) => self.disconnect(&ErrorKind::WriteZero.into()),
+                Ok(n) => self.channel.advance_output(n),
+                Err(err) => self.disconnect(&err),
+            }
+        }
+    }
+
+    fn poll_recv(&mut self) -> Poll<Result<(), Error>> {
+        let waker = self.waker.clone();
+        let cx = &mut Context::from_waker(&waker);
+        let mut buf = [0; 8 * 1024];
+        loop {
+            // HELLO of new connection needs sending
+            if self.check()? {
+                return Poll::Ready(Ok(()));
+            }
+            let conn = match &mut self.conn {
+                Some(conn) => conn,
+                None => return Poll::Pending,
+            };
+            let mut read_buf = ReadBuf::new(&mut buf);
+            match ready!(Pin::new(conn).poll_read(cx, &mut read_buf)) {
+                Ok(()) if read_buf.filled().is_empty() => {
+                    self.disconnect(&ErrorKind::UnexpectedEof.into())
+                }
+                Ok(()) => {
+                    let res = self.channel.feed(read_buf.filled());
+                    self.fail(res)?;
+                    return Poll::Ready(Ok(()));
+                }
+                Err(err) => self.disconnect(&err),
+            }
+        }
+    }
+}
+
+impl Drop for SessionStream {
+    fn drop(&mut self) {
+        self.slot.lock().unwrap().dropped = true;
+        self.listener.wake();
+    }
+}
+
+impl AsyncRead for SessionStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        self.get_mut().poll_read_data(cx, buf)
+    }
+}
+
+impl AsyncWrite for SessionStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        self.get_mut().poll_write_data(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        Wakers::register(&this.wakers.write, cx.waker());
+        let waker = this.waker.clone();
+        loop {
+            ready!(this.poll_send())?;
+            let conn = match &mut this.conn {
+                Some(conn) => conn,
+                None => return Poll::Pending,
+            };
+            match ready!(Pin::new(conn).poll_flush(&mut Context::from_waker(&waker))) {
+                Ok(()) => return Poll::Ready(Ok(())),
+                Err(err) => this.disconnect(&err),
+            }
+        }
+    }
+
+    /// Send end of data and wait until client reads all of it.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.get_mut().poll_close(cx)
+    }
+}
@@ -0,0 +1,216 @@
+//! [MessageSink] writing whole messages over [RetryingStream].
+
+use std::collections::VecDeque;
+use std::mem;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::Bytes;
+use futures::{Sink, SinkExt};
+use log::debug;
+use tokio::io::{AsyncWrite, Error};
+
+use crate::connector::Connector;
+use crate::error::is_reconnecting;
+use crate::stream::{yield_now, RetryingStream};
+use crate::tcp::TcpConnector;
+
+/// [MessageSink] over [RetryingTcpStream](crate::RetryingTcpStream).
+pub type TcpMessageSink = MessageSink<TcpConnector>;
+
+/// [Sink] of messages, each written whole to one connection.
+///
+/// With byte writes connection can break in the middle of message, then peer gets its beginning
+/// and the rest is written to the next connection. Sink keeps message until it was written and
+/// flushed; when connection breaks before that, whole message is written again to the new one.
+/// Messages peer didn't acknowledge before connection broke, counted with
+/// [Connector::unacked_bytes], are written again whole as well, before the next one. Errors of
+/// broken connection are not returned; other errors, e.g.
+/// [GaveUp](crate::ErrorCategory::GaveUp), are.
+///
+/// Peer can get the beginning of message on broken connection, and message twice when it
+/// received it but acknowledgement was lost, so messages should be framed and idempotent.
+/// They are written to transport directly, not through
+/// [replay buffer](RetryingStream::set_replay_policy) or
+/// [write queue](RetryingStream::set_write_queue). `poll_ready` waits until previous message
+/// was written, `poll_close` writes the last one and shuts the stream down.
+pub struct MessageSink<C: Connector> {
+    stream: RetryingStream<C>,
+    // messages to write to current connection, the last one is new, others were not
+    // acknowledged on previous connection
+    pending: VecDeque<Bytes>,
+    // bytes of the first pending message written to current connection
+    written: usize,
+    // messages written whole to current connection that peer may not have acknowledged
+    in_flight: VecDeque<Bytes>,
+    in_flight_len: usize,
+    // connection messages are written to, see RetryingStream::connects
+    connection: u64,
+}
+
+impl<C: Connector> MessageSink<C> {
+    pub fn new(stream: RetryingStream<C>) -> Self {
+        let connection = stream.connects();
+        Self {
+            stream,
+            pending: VecDeque::new(),
+            written: 0,
+            in_flight: VecDeque::new(),
+            in_flight_len: 0,
+            connection,
+        }
+    }
+
+    /// Write `message` and flush the stream. Completes when it was written whole to one
+    /// connection.
+    pub async fn write_message<B: Into<Bytes>>(&mut self, message: B) -> Result<(), Error> {
+        self.send(message.into()).await
+    }
+
+    pub fn get_ref(&self) -> &RetryingStream<C> {
+        &self.stream
+    }
+
+    /// Stream messages are written to. Writing it directly mixes data with messages.
+    pub fn get_mut(&mut self) -> &mut RetryingStream<C> {
+        &mut self.stream
+    }
+
+    /// Return the stream, messages not written yet are dropped.
+    pub fn into_inner(self) -> RetryingStream<C> {
+        self.stream
+    }
+
+    // Write and flush pending messages, start again on new connection
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while !self.pending.is_empty() {
+            ready!(self.stream.poll_reconnect(cx))?;
+            if self.stream.connects() != self.connection {
+                self.on_new_connection();
+            }
+            let message = &self.pending[0];
+            if self.written < message.len() {
+                match ready!(self
+                    .stream
+                    .poll_write_connected(cx, &message[self.written..]))
+                {
+                    Ok(n) => self.written += n,
+                    Err(err) if is_reconnecting(&err) => return yield_now(cx),
+                    Err(err) => return Poll::Ready(Err(err)),
+                }
+                continue;
+            }
+            if self.pending.len() > 1 {
+                self.on_written();
+                continue;
+            }
+            match ready!(Pin::new(&mut self.stream).poll_flush(cx)) {
+                // flush may reconnect, then messages are written again
+                Ok(()) if self.stream.connects() == self.connection => self.on_written(),
+                Ok(()) => {}
+                Err(err) if is_reconnecting(&err) => {}
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // First pending message was written whole. Drop messages peer acknowledged, all of them
+    // when connector can't tell.
+    fn on_written(&mut self) {
+        if let Some(message) = self.pending.pop_front() {
+            self.in_flight_len += message.len();
+            self.in_flight.push_back(message);
+        }
+        self.written = 0;
+        let unacked = self.stream.unacked_bytes().unwrap_or(0);
+        self.forget_written(unacked);
+    }
+
+    // Keep messages with any of `unacked` last bytes written to connection
+    fn forget_written(&mut self, unacked: usize) {
+        let mut acked = (self.in_flight_len + self.written).saturating_sub(unacked);
+        while let Some(message) = self.in_flight.front() {
+            if message.len() > acked {
+                break;
+            }
+            acked -= message.len();
+            self.in_flight_len -= message.len();
+            self.in_flight.pop_front();
+        }
+    }
+
+    // Connection changed, write messages peer didn't get to the new one before pending ones
+    fn on_new_connection(&mut self) {
+        match self.stream.lost_unacked(self.connection) {
+            Some(unacked) => self.forget_written(unacked),
+            // lost connection may not be the one messages were written to
+            None if self.stream.connects() > self.connection + 1 => {}
+            None => self.forget_written(0),
+        }
+        if !self.in_flight.is_empty() || self.written > 0 {
+            debug!(
+                "MessageSink => write {} messages again",
+                self.in_flight.len() + usize::from(self.written > 0)
+            );
+        }
+        let mut resend = mem::take(&mut self.in_flight);
+        resend.append(&mut self.pending);
+        self.pending = resend;
+        self.in_flight_len = 0;
+        self.written = 0;
+        self.connection = self.stream.connects();
+    }
+}
+
+impl<C: Connector> Sink<Bytes> for MessageSink<C> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Bytes) -> Result<(), Error> {
+        self.get_mut().pending.push_back(message);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, ErrorKind};
+
+    use super::*;
+    use crate::connector::mock::{Fault, MockConnector};
+
+    #[tokio::test]
+    async fn unacknowledged_message_is_resent_before_next_one() {
+        let connector = MockConnector::default();
+        let mut first = connector.accept("a");
+        let mut second = connector.accept("a");
+        let mut sink = MessageSink::new(RetryingStream::new(connector, "a"));
+        sink.write_message(&b"first"[..]).await.unwrap();
+        // peer acknowledged everything but the second message
+        first.set_unacked(Some(6));
+        sink.write_message(&b"second"[..]).await.unwrap();
+        let mut buf = [0; 11];
+        first.io.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"firstsecond");
+
+        first.inject(Fault::Error(ErrorKind::ConnectionReset));
+        sink.write_message(&b"third"[..]).await.unwrap();
+        let mut buf = [0; 11];
+        second.io.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"secondthird");
+    }
+}
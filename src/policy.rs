@@ -0,0 +1,232 @@
+//! Policies deciding how long [RetryingTcpStream] waits before next reconnect attempt.
+//!
+//! [RetryingTcpStream]: crate::RetryingTcpStream
+
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Decide how long to wait before next reconnect attempt.
+///
+/// `attempt` is a number of consecutive reconnects since the connection was last stable
+/// (starting from 1). When connection stays up for long enough [reset](ReconnectPolicy::reset) is
+/// called and counting start again.
+pub trait ReconnectPolicy: Send {
+    /// Return delay before reconnect attempt number `attempt`.
+    fn next_delay(&mut self, attempt: u32) -> Duration;
+
+    /// Forget any state kept between attempts.
+    fn reset(&mut self) {}
+}
+
+/// Wait the same amount of time before each attempt.
+///
+/// `ConstantBackoff::new(Duration::from_secs(0))` reconnects immediately. This is the default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstantBackoff {
+    delay: Duration,
+}
+
+impl ConstantBackoff {
+    pub fn new(delay: Duration) -> Self {
+        Self { delay }
+    }
+}
+
+impl Default for ConstantBackoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(0))
+    }
+}
+
+impl ReconnectPolicy for ConstantBackoff {
+    fn next_delay(&mut self, _attempt: u32) -> Duration {
+        self.delay
+    }
+}
+
+/// Delay grows by `step` after each attempt: `initial + step * (attempt - 1)`, up to `max`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinearBackoff {
+    initial: Duration,
+    step: Duration,
+    max: Duration,
+}
+
+impl LinearBackoff {
+    pub fn new(initial: Duration, step: Duration, max: Duration) -> Self {
+        Self { initial, step, max }
+    }
+}
+
+impl ReconnectPolicy for LinearBackoff {
+    fn next_delay(&mut self, attempt: u32) -> Duration {
+        let grow = self
+            .step
+            .checked_mul(attempt.saturating_sub(1))
+            .unwrap_or(self.max);
+        self.initial
+            .checked_add(grow)
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+}
+
+/// Delay is multiplied by `factor` after each attempt: `initial * factor^(attempt - 1)`, up to `max`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExponentialBackoff {
+    initial: Duration,
+    factor: u32,
+    max: Duration,
+}
+
+impl ExponentialBackoff {
+    pub fn new(initial: Duration, factor: u32, max: Duration) -> Self {
+        Self {
+            initial,
+            factor,
+            max,
+        }
+    }
+}
+
+impl ReconnectPolicy for ExponentialBackoff {
+    fn next_delay(&mut self, attempt: u32) -> Duration {
+        self.factor
+            .checked_pow(attempt.saturating_sub(1))
+            .and_then(|mul| self.initial.checked_mul(mul))
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+}
+
+/// "Decorrelated jitter" from [AWS Architecture Blog].
+///
+/// Each delay is random value between `base` and three times previous delay, up to `max`.
+///
+/// # Panics
+///
+/// [new](DecorrelatedJitter::new) panics if `base` is zero, every delay would be zero then.
+///
+/// [AWS Architecture Blog]: https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecorrelatedJitter {
+    base: Duration,
+    max: Duration,
+    prev: Duration,
+}
+
+impl DecorrelatedJitter {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        assert!(!base.is_zero(), "DecorrelatedJitter base is zero");
+        Self {
+            base,
+            max,
+            prev: base,
+        }
+    }
+}
+
+impl ReconnectPolicy for DecorrelatedJitter {
+    fn next_delay(&mut self, _attempt: u32) -> Duration {
+        let upper = self.prev.checked_mul(3).unwrap_or(self.max).max(self.base);
+        let delay = rand::thread_rng()
+            .gen_range(self.base..=upper)
+            .min(self.max);
+        self.prev = delay;
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.prev = self.base;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn linear_starts_with_initial() {
+        let mut policy = LinearBackoff::new(MS * 100, MS * 50, MS * 1000);
+        assert_eq!(policy.next_delay(1), MS * 100);
+        assert_eq!(policy.next_delay(2), MS * 150);
+        assert_eq!(policy.next_delay(3), MS * 200);
+        // attempt 0 is not used, but doesn't underflow
+        assert_eq!(policy.next_delay(0), MS * 100);
+    }
+
+    #[test]
+    fn linear_stops_at_max() {
+        let mut policy = LinearBackoff::new(MS * 100, MS * 50, MS * 1000);
+        assert_eq!(policy.next_delay(19), MS * 1000);
+        assert_eq!(policy.next_delay(20), MS * 1000);
+        assert_eq!(policy.next_delay(u32::MAX), MS * 1000);
+    }
+
+    #[test]
+    fn linear_overflow_gives_max() {
+        let mut policy = LinearBackoff::new(Duration::MAX, Duration::MAX, MS * 1000);
+        assert_eq!(policy.next_delay(1), MS * 1000);
+        assert_eq!(policy.next_delay(2), MS * 1000);
+        assert_eq!(policy.next_delay(u32::MAX), MS * 1000);
+    }
+
+    #[test]
+    fn exponential_starts_with_initial() {
+        let mut policy = ExponentialBackoff::new(MS * 100, 2, MS * 1000);
+        assert_eq!(policy.next_delay(1), MS * 100);
+        assert_eq!(policy.next_delay(2), MS * 200);
+        assert_eq!(policy.next_delay(3), MS * 400);
+        assert_eq!(policy.next_delay(4), MS * 800);
+        assert_eq!(policy.next_delay(5), MS * 1000);
+        assert_eq!(policy.next_delay(0), MS * 100);
+    }
+
+    #[test]
+    fn exponential_overflow_gives_max() {
+        let mut policy = ExponentialBackoff::new(MS * 100, 2, MS * 1000);
+        // 2^32 doesn't fit u32
+        assert_eq!(policy.next_delay(34), MS * 1000);
+        assert_eq!(policy.next_delay(u32::MAX), MS * 1000);
+        // factor fits, product doesn't fit Duration
+        let mut policy = ExponentialBackoff::new(Duration::MAX / 2, 3, Duration::MAX);
+        assert_eq!(policy.next_delay(2), Duration::MAX);
+    }
+
+    #[test]
+    fn constant_ignores_attempt() {
+        let mut policy = ConstantBackoff::new(MS * 300);
+        assert_eq!(policy.next_delay(1), MS * 300);
+        assert_eq!(policy.next_delay(u32::MAX), MS * 300);
+        assert_eq!(ConstantBackoff::default().next_delay(1), Duration::ZERO);
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let mut policy = DecorrelatedJitter::new(MS * 10, MS * 500);
+        let mut prev = MS * 10;
+        for attempt in 1..1000 {
+            let delay = policy.next_delay(attempt);
+            assert!(delay >= MS * 10, "{:?}", delay);
+            assert!(delay <= (prev * 3).min(MS * 500), "{:?}", delay);
+            prev = delay;
+        }
+        policy.reset();
+        assert!(policy.next_delay(1) <= MS * 30);
+    }
+
+    #[test]
+    fn jitter_base_above_max() {
+        let mut policy = DecorrelatedJitter::new(MS * 100, MS * 50);
+        assert_eq!(policy.next_delay(1), MS * 50);
+    }
+
+    #[test]
+    #[should_panic(expected = "base is zero")]
+    fn jitter_rejects_zero_base() {
+        DecorrelatedJitter::new(Duration::ZERO, MS * 500);
+    }
+}
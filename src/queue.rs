@@ -0,0 +1,245 @@
+//! Accepting writes while [RetryingStream](crate::RetryingStream) is reconnecting.
+
+use std::collections::VecDeque;
+
+use tokio::io::Error;
+
+use crate::spool::Spool;
+
+/// What happens when [QueuePolicy::capacity] bytes are queued and more are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum QueueOverflow {
+    /// Write returns `Pending` until connection is established and queue is written to it.
+    #[default]
+    Block,
+    /// Forget the oldest queued bytes to make room for new ones.
+    DropOldest,
+    /// Forget bytes that don't fit, write still reports them as written.
+    DropNewest,
+    /// Write fails with [QueueFull](crate::ErrorCategory::QueueFull) error.
+    Error,
+}
+
+/// Configuration of write queue set with
+/// [set_write_queue](crate::RetryingStream::set_write_queue) or of [Spool].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QueuePolicy {
+    /// Maximum number of queued bytes.
+    pub capacity: usize,
+    pub overflow: QueueOverflow,
+}
+
+impl QueuePolicy {
+    pub fn new(capacity: usize, overflow: QueueOverflow) -> Self {
+        Self { capacity, overflow }
+    }
+}
+
+// Bytes written while not connected, in order they were written
+pub(crate) struct WriteQueue {
+    policy: QueuePolicy,
+    store: Store,
+    // bytes forgotten because queue was full
+    dropped: u64,
+}
+
+enum Store {
+    Memory(VecDeque<u8>),
+    Spool(Spool),
+}
+
+impl WriteQueue {
+    pub(crate) fn new(policy: QueuePolicy) -> Self {
+        Self {
+            policy,
+            store: Store::Memory(VecDeque::new()),
+            dropped: 0,
+        }
+    }
+
+    pub(crate) fn with_spool(spool: Spool) -> Self {
+        Self {
+            policy: *spool.policy(),
+            store: Store::Spool(spool),
+            dropped: 0,
+        }
+    }
+
+    pub(crate) fn policy(&self) -> &QueuePolicy {
+        &self.policy
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match &self.store {
+            Store::Memory(data) => data.len(),
+            Store::Spool(spool) => spool.len() as usize,
+        }
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    // Queue bytes from `buf`, return how many of them write reports. None when queue is full and
+    // write has to wait or fail.
+    pub(crate) fn push(&mut self, buf: &[u8]) -> Result<Option<usize>, Error> {
+        let capacity = self.policy.capacity;
+        let room = capacity.saturating_sub(self.len());
+        match self.policy.overflow {
+            QueueOverflow::Block | QueueOverflow::Error => {
+                if room == 0 && !buf.is_empty() {
+                    return Ok(None);
+                }
+                let n = buf.len().min(room);
+                self.append(&buf[..n])?;
+                Ok(Some(n))
+            }
+            QueueOverflow::DropOldest => {
+                let kept = &buf[buf.len().saturating_sub(capacity)..];
+                let excess = (self.len() + kept.len()).saturating_sub(capacity);
+                self.advance(excess)?;
+                self.append(kept)?;
+                self.dropped += (excess + buf.len() - kept.len()) as u64;
+                Ok(Some(buf.len()))
+            }
+            QueueOverflow::DropNewest => {
+                let n = buf.len().min(room);
+                self.append(&buf[..n])?;
+                self.dropped += (buf.len() - n) as u64;
+                Ok(Some(buf.len()))
+            }
+        }
+    }
+
+    fn append(&mut self, buf: &[u8]) -> Result<(), Error> {
+        match &mut self.store {
+            Store::Memory(data) => data.extend(buf),
+            Store::Spool(spool) => spool.append(buf)?,
+        }
+        Ok(())
+    }
+
+    // Oldest queued bytes, empty when queue is
+    pub(crate) fn front(&mut self) -> Result<&[u8], Error> {
+        match &mut self.store {
+            Store::Memory(data) => Ok(data.as_slices().0),
+            Store::Spool(spool) => spool.front(),
+        }
+    }
+
+    pub(crate) fn advance(&mut self, n: usize) -> Result<(), Error> {
+        match &mut self.store {
+            Store::Memory(data) => {
+                data.drain(..n);
+            }
+            Store::Spool(spool) => spool.consume(n)?,
+        }
+        Ok(())
+    }
+
+    // Stream was closed. Memory queue can't be written any more, spool keeps data for next
+    // stream using the file.
+    pub(crate) fn on_closed(&mut self) {
+        if let Store::Memory(data) = &mut self.store {
+            data.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::spool::SyncPolicy;
+
+    fn spooled(dir: &tempfile::TempDir, overflow: QueueOverflow) -> WriteQueue {
+        let policy = QueuePolicy::new(8, overflow);
+        let spool = Spool::open(dir.path().join("spool"), policy, SyncPolicy::Never).unwrap();
+        WriteQueue::with_spool(spool)
+    }
+
+    // Same queue in memory and in spool file
+    fn queues(dir: &tempfile::TempDir, overflow: QueueOverflow) -> [WriteQueue; 2] {
+        let memory = WriteQueue::new(QueuePolicy::new(8, overflow));
+        [memory, spooled(dir, overflow)]
+    }
+
+    // Read everything left, consuming it
+    pub(crate) fn drain(queue: &mut WriteQueue) -> Vec<u8> {
+        let mut data = Vec::new();
+        loop {
+            let front = queue.front().unwrap();
+            if front.is_empty() {
+                return data;
+            }
+            let n = front.len();
+            data.extend_from_slice(front);
+            queue.advance(n).unwrap();
+        }
+    }
+
+    #[test]
+    fn blocks_when_full() {
+        let dir = tempfile::tempdir().unwrap();
+        for overflow in [QueueOverflow::Block, QueueOverflow::Error] {
+            for mut queue in queues(&dir, overflow) {
+                assert_eq!(queue.push(b"012345").unwrap(), Some(6));
+                assert_eq!(queue.push(b"6789").unwrap(), Some(2));
+                assert_eq!(queue.push(b"89").unwrap(), None);
+                assert_eq!(queue.len(), 8);
+                assert_eq!(queue.dropped(), 0);
+                assert_eq!(drain(&mut queue), b"01234567");
+                assert_eq!(queue.push(b"89").unwrap(), Some(2));
+                assert_eq!(drain(&mut queue), b"89");
+            }
+        }
+    }
+
+    #[test]
+    fn drops_oldest() {
+        let dir = tempfile::tempdir().unwrap();
+        for mut queue in queues(&dir, QueueOverflow::DropOldest) {
+            assert_eq!(queue.push(b"012345").unwrap(), Some(6));
+            assert_eq!(queue.push(b"6789").unwrap(), Some(4));
+            assert_eq!(queue.len(), 8);
+            assert_eq!(queue.dropped(), 2);
+            // longer than capacity, only its end is kept
+            assert_eq!(queue.push(b"abcdefghij").unwrap(), Some(10));
+            assert_eq!(queue.dropped(), 12);
+            assert_eq!(drain(&mut queue), b"cdefghij");
+        }
+    }
+
+    #[test]
+    fn drops_newest() {
+        let dir = tempfile::tempdir().unwrap();
+        for mut queue in queues(&dir, QueueOverflow::DropNewest) {
+            assert_eq!(queue.push(b"012345").unwrap(), Some(6));
+            assert_eq!(queue.push(b"6789").unwrap(), Some(4));
+            assert_eq!(queue.push(b"ab").unwrap(), Some(2));
+            assert_eq!(queue.len(), 8);
+            assert_eq!(queue.dropped(), 4);
+            assert_eq!(drain(&mut queue), b"01234567");
+        }
+    }
+
+    #[test]
+    fn memory_queue_is_dropped_on_close() {
+        let mut queue = WriteQueue::new(QueuePolicy::new(8, QueueOverflow::Block));
+        queue.push(b"0123").unwrap();
+        queue.on_closed();
+        assert_eq!(queue.len(), 0);
+        assert!(queue.front().unwrap().is_empty());
+    }
+
+    #[test]
+    fn spool_keeps_overflowed_queue_on_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = spooled(&dir, QueueOverflow::DropOldest);
+        queue.push(b"0123456789").unwrap();
+        queue.on_closed();
+        drop(queue);
+        let mut queue = spooled(&dir, QueueOverflow::DropOldest);
+        assert_eq!(queue.len(), 8);
+        assert_eq!(drain(&mut queue), b"23456789");
+    }
+}
@@ -0,0 +1,177 @@
+//! Resending bytes that were written but not acknowledged when connection broke.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// How often full buffer with Overflow::Block checks for acknowledged bytes
+pub(crate) const REPLAY_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What happens when [ReplayPolicy::capacity] bytes are retained and more are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Overflow {
+    /// Forget the oldest bytes, they will not be resent.
+    #[default]
+    DropOldest,
+    /// Write returns `Pending` until peer acknowledges enough bytes. Needs
+    /// [Connector::unacked_bytes](crate::Connector::unacked_bytes), otherwise behaves like
+    /// `DropOldest`.
+    Block,
+}
+
+/// Configuration of replay buffer set with
+/// [set_replay_policy](crate::RetryingStream::set_replay_policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReplayPolicy {
+    /// Maximum number of retained bytes.
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+impl ReplayPolicy {
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        Self { capacity, overflow }
+    }
+}
+
+// Recently written bytes. Bytes before `sent` were written to transport, rest has to be resent
+// on new connection before any new data.
+pub(crate) struct ReplayBuffer {
+    policy: ReplayPolicy,
+    data: VecDeque<u8>,
+    sent: usize,
+}
+
+impl ReplayBuffer {
+    pub(crate) fn new(policy: ReplayPolicy) -> Self {
+        Self {
+            policy,
+            data: VecDeque::with_capacity(policy.capacity),
+            sent: 0,
+        }
+    }
+
+    pub(crate) fn policy(&self) -> &ReplayPolicy {
+        &self.policy
+    }
+
+    // Bytes that can be written without dropping anything
+    pub(crate) fn free(&self) -> usize {
+        self.policy.capacity.saturating_sub(self.data.len())
+    }
+
+    // Remember bytes written to transport
+    pub(crate) fn record(&mut self, written: &[u8]) {
+        self.data.extend(written);
+        self.sent += written.len();
+        let excess = self.data.len().saturating_sub(self.policy.capacity);
+        if excess > 0 {
+            self.data.drain(..excess);
+            self.sent = self.sent.saturating_sub(excess);
+        }
+    }
+
+    // Forget sent bytes that were acknowledged, `unacked` is number of sent bytes that weren't
+    pub(crate) fn acknowledge(&mut self, unacked: usize) {
+        let acked = self.sent.saturating_sub(unacked);
+        self.data.drain(..acked);
+        self.sent -= acked;
+    }
+
+    // Connection broke. When number of unacknowledged bytes is unknown everything is resent.
+    pub(crate) fn on_disconnect(&mut self, unacked: Option<usize>) {
+        if let Some(unacked) = unacked {
+            self.acknowledge(unacked);
+        }
+        self.sent = 0;
+    }
+
+    // Bytes waiting to be resent
+    pub(crate) fn pending(&self) -> &[u8] {
+        let (front, back) = self.data.as_slices();
+        if self.sent < front.len() {
+            &front[self.sent..]
+        } else {
+            &back[self.sent - front.len()..]
+        }
+    }
+
+    pub(crate) fn advance(&mut self, n: usize) {
+        self.sent += n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(capacity: usize) -> ReplayBuffer {
+        ReplayBuffer::new(ReplayPolicy::new(capacity, Overflow::DropOldest))
+    }
+
+    // Everything waiting to be resent, advancing like writes to new connection do
+    fn resend(replay: &mut ReplayBuffer) -> Vec<u8> {
+        let mut resent = Vec::new();
+        while !replay.pending().is_empty() {
+            let chunk = replay.pending().to_vec();
+            replay.advance(chunk.len());
+            resent.extend(chunk);
+        }
+        resent
+    }
+
+    #[test]
+    fn recorded_bytes_are_not_pending() {
+        let mut replay = buffer(8);
+        replay.record(b"abc");
+        assert!(replay.pending().is_empty());
+        assert_eq!(replay.free(), 5);
+    }
+
+    #[test]
+    fn unacknowledged_bytes_are_resent() {
+        let mut replay = buffer(8);
+        replay.record(b"abcdef");
+        replay.acknowledge(4);
+        assert_eq!(replay.free(), 4);
+        replay.on_disconnect(Some(1));
+        assert_eq!(resend(&mut replay), b"f");
+        // resent bytes are retained for next connection
+        replay.on_disconnect(None);
+        assert_eq!(resend(&mut replay), b"f");
+    }
+
+    #[test]
+    fn unknown_unacked_resends_everything() {
+        let mut replay = buffer(8);
+        replay.record(b"abc");
+        replay.on_disconnect(None);
+        assert_eq!(replay.pending(), b"abc");
+        replay.advance(1);
+        assert_eq!(replay.pending(), b"bc");
+    }
+
+    #[test]
+    fn over_capacity_drops_oldest() {
+        let mut replay = buffer(4);
+        replay.record(b"abcdef");
+        assert_eq!(replay.free(), 0);
+        // more unacknowledged than retained, only what is left can be resent
+        replay.on_disconnect(Some(6));
+        assert_eq!(resend(&mut replay), b"cdef");
+    }
+
+    #[test]
+    fn pending_continues_after_wrap_around() {
+        let mut replay = buffer(4);
+        replay.record(b"abcd");
+        replay.acknowledge(3);
+        replay.record(b"ef");
+        replay.on_disconnect(Some(5));
+        assert_eq!(resend(&mut replay), b"cdef");
+
+        // new data is recorded after resent bytes
+        replay.record(b"gh");
+        replay.on_disconnect(None);
+        assert_eq!(resend(&mut replay), b"efgh");
+    }
+}
@@ -0,0 +1,136 @@
+//! Resolving host names for [TcpConnector](crate::TcpConnector).
+//!
+//! Host names are resolved again before every reconnect so stream follows service that moved to
+//! new IP.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::Error;
+
+/// Future returned by [Resolver].
+pub type ResolveFuture = Pin<Box<dyn Future<Output = Result<Vec<SocketAddr>, Error>> + Send>>;
+
+/// Asynchronously resolve `host` and `port` into list of addresses.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture;
+}
+
+/// Resolve with [tokio::net::lookup_host]. This is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioResolver;
+
+impl Resolver for TokioResolver {
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture {
+        let host = host.to_owned();
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host.as_str(), port)).await?;
+            Ok(addrs.collect())
+        })
+    }
+}
+
+type Cache = HashMap<(String, u16), (Instant, Vec<SocketAddr>)>;
+
+/// Remember addresses returned by inner [Resolver] for `ttl`.
+pub struct CachingResolver<R> {
+    inner: R,
+    ttl: Duration,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    pub fn new(inner: R, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Forget all cached entries.
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture {
+        let key = (host.to_owned(), port);
+        if let Some((at, addrs)) = self.cache.lock().unwrap().get(&key) {
+            if at.elapsed() < self.ttl {
+                let addrs = addrs.clone();
+                return Box::pin(async move { Ok(addrs) });
+            }
+        }
+
+        let resolve = self.inner.resolve(host, port);
+        let cache = self.cache.clone();
+        Box::pin(async move {
+            let addrs = resolve.await?;
+            cache
+                .lock()
+                .unwrap()
+                .insert(key, (Instant::now(), addrs.clone()));
+            Ok(addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Returns port as the only address, counts calls
+    #[derive(Default)]
+    struct Counting {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Resolver for Counting {
+        fn resolve(&self, _host: &str, port: u16) -> ResolveFuture {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            Box::pin(async move { Ok(vec![addr]) })
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_is_used_until_ttl() {
+        let inner = Counting::default();
+        let calls = inner.calls.clone();
+        let resolver = CachingResolver::new(inner, Duration::from_millis(50));
+        let addr = resolver.resolve("a", 1).await.unwrap();
+        assert_eq!(resolver.resolve("a", 1).await.unwrap(), addr);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // other host or port is other entry
+        resolver.resolve("b", 1).await.unwrap();
+        assert_eq!(resolver.resolve("a", 2).await.unwrap()[0].port(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        std::thread::sleep(Duration::from_millis(60));
+        resolver.resolve("a", 1).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        resolver.resolve("a", 1).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        resolver.clear();
+        resolver.resolve("a", 1).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn zero_ttl_always_resolves() {
+        let inner = Counting::default();
+        let calls = inner.calls.clone();
+        let resolver = CachingResolver::new(inner, Duration::ZERO);
+        resolver.resolve("a", 1).await.unwrap();
+        resolver.resolve("a", 1).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
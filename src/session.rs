@@ -0,0 +1,292 @@
+//! [Session] giving byte stream that survives reconnects without loss or duplication.
+
+use std::future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll, Waker};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Error, ErrorKind, ReadBuf};
+
+pub use crate::channel::DEFAULT_MAX_UNACKED;
+use crate::channel::{Channel, Endpoint, Hello, Wakers, HELLO_LEN, HELLO_TIMEOUT};
+use crate::classify::EofPolicy;
+use crate::connector::Connector;
+use crate::error::is_reconnecting;
+use crate::handshake::HandshakeFuture;
+use crate::stream::{yield_now, RetryingStream};
+use crate::tcp::TcpConnector;
+
+/// [Session] over [RetryingTcpStream](crate::RetryingTcpStream).
+pub type TcpSession = Session<TcpConnector>;
+
+// State shared with resume handshake
+struct Resume {
+    // 0 until peer assigns one
+    session_id: u64,
+    received: u64,
+    // position peer sent in HELLO on new connection, not applied yet
+    peer_received: Option<u64>,
+    // peer doesn't know the session any more
+    lost: bool,
+}
+
+/// Reliable byte stream layered on [RetryingStream].
+///
+/// Data is sent in frames with sequence numbers. Each side keeps bytes until peer acknowledges
+/// reading them, at most [set_max_unacked](Session::set_max_unacked) of them. On every new
+/// connection both sides exchange session id and number of bytes received, then continue
+/// exactly where they left off, so application sees neither lost nor duplicated data.
+///
+/// Peer has to speak the same protocol, e.g. [SessionListener](crate::SessionListener).
+/// Session runs its exchange as [Handshake](crate::Handshake) after the one set on the stream
+/// and sets [EofPolicy::Reconnect]. Errors of broken connection are not returned; other errors,
+/// e.g. [GaveUp](crate::ErrorCategory::GaveUp), are. When peer doesn't know the session any
+/// more, e.g. it expired, session fails with `ConnectionAborted` error.
+///
+/// Every connection starts with both sides sending 20 bytes: `RTS1`, session id and number of
+/// bytes received, both big endian `u64`. Connection whose peer doesn't answer within 10 seconds
+/// counts as failed connect attempt. Client sends id 0 to open new session; server answers
+/// with id of the new one, or 0 when it doesn't know the requested one. Then both sides send
+/// frames with big endian fields: `1, seq: u64, len: u32, data` for data, `2, seq: u64` to
+/// acknowledge everything before `seq` was read and `3, seq: u64` for end of data. `seq` counts
+/// data bytes since start of the session, end of data takes one number.
+///
+/// `poll_write` only copies data to the session and tries to send it. Flush to make sure it was
+/// written to transport. `poll_shutdown` sends end of data and completes when peer read all of
+/// it; reading still works afterwards. Connection is closed when session is dropped.
+/// Acknowledgement of peer's end of data can be lost with connection, then peer waits for
+/// reconnect, which happens only while the session is polled.
+pub struct Session<C: Connector> {
+    stream: RetryingStream<C>,
+    channel: Channel,
+    resume: Arc<Mutex<Resume>>,
+    // session can't continue
+    failed: Option<Error>,
+    wakers: Arc<Wakers>,
+    // wakes all of `wakers`, used for every poll of the stream
+    waker: Waker,
+}
+
+impl<C: Connector> Session<C>
+where
+    C::Transport: Send + 'static,
+{
+    /// Start new session over `stream`. Connection is started on first poll.
+    pub fn new(mut stream: RetryingStream<C>) -> Self {
+        let resume = Arc::new(Mutex::new(Resume {
+            session_id: 0,
+            received: 0,
+            peer_received: None,
+            lost: false,
+        }));
+        let shared = resume.clone();
+        let mut login = stream.handshake.take();
+        stream.set_handshake(move |transport: C::Transport| {
+            let login: HandshakeFuture<C::Transport> = match &mut login {
+                Some(login) => login.handshake(transport),
+                None => Box::pin(future::ready(Ok(transport))),
+            };
+            let resume = shared.clone();
+            async move { resume_session(login.await?, resume).await }
+        });
+        stream.set_eof_policy(EofPolicy::Reconnect);
+        let wakers = Arc::new(Wakers::default());
+        Self {
+            stream,
+            channel: Channel::new(DEFAULT_MAX_UNACKED),
+            resume,
+            failed: None,
+            waker: Waker::from(wakers.clone()),
+            wakers,
+        }
+    }
+}
+
+// Exchange HELLO on new connection. Peer that doesn't answer in time fails the attempt, so that
+// stream reconnects or gives up.
+async fn resume_session<T>(transport: T, resume: Arc<Mutex<Resume>>) -> Result<T, Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    match tokio::time::timeout(HELLO_TIMEOUT, exchange_hello(transport, resume)).await {
+        Ok(res) => res,
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "HELLO timed out")),
+    }
+}
+
+async fn exchange_hello<T>(mut transport: T, resume: Arc<Mutex<Resume>>) -> Result<T, Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let hello = {
+        let resume = resume.lock().unwrap();
+        Hello {
+            session_id: resume.session_id,
+            received: resume.received,
+        }
+    };
+    transport.write_all(&hello.encode()).await?;
+    transport.flush().await?;
+    let mut reply = [0; HELLO_LEN];
+    transport.read_exact(&mut reply).await?;
+    let reply = Hello::decode(&reply)?;
+    let mut resume = resume.lock().unwrap();
+    if reply.session_id == 0 || (hello.session_id != 0 && reply.session_id != hello.session_id) {
+        resume.lost = true;
+    } else {
+        resume.session_id = reply.session_id;
+        resume.peer_received = Some(reply.received);
+    }
+    Ok(transport)
+}
+
+impl<C: Connector> Session<C> {
+    /// Set how many written bytes can wait for acknowledgement. Writes return `Pending` when
+    /// there are more. Default is [DEFAULT_MAX_UNACKED].
+    pub fn set_max_unacked(&mut self, max_unacked: usize) {
+        self.channel.set_max_unacked(max_unacked);
+    }
+
+    /// Id assigned by peer, `None` before first connection.
+    pub fn session_id(&self) -> Option<u64> {
+        match self.resume.lock().unwrap().session_id {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    /// Stream session runs over.
+    pub fn get_ref(&self) -> &RetryingStream<C> {
+        &self.stream
+    }
+
+    /// Stream session runs over. Reading or writing it directly breaks the session.
+    pub fn get_mut(&mut self) -> &mut RetryingStream<C> {
+        &mut self.stream
+    }
+}
+
+impl<C: Connector> Endpoint for Session<C> {
+    fn channel(&mut self) -> &mut Channel {
+        &mut self.channel
+    }
+
+    fn wakers(&self) -> &Wakers {
+        &self.wakers
+    }
+
+    fn failed(&mut self) -> &mut Option<Error> {
+        &mut self.failed
+    }
+
+    fn resume(&mut self) -> Result<bool, Error> {
+        let mut resume = self.resume.lock().unwrap();
+        if resume.lost {
+            Err(Error::new(
+                ErrorKind::ConnectionAborted,
+                "peer doesn't know the session",
+            ))
+        } else if let Some(peer_received) = resume.peer_received.take() {
+            self.channel.resume(peer_received).map(|()| true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn poll_send(&mut self) -> Poll<Result<(), Error>> {
+        let waker = self.waker.clone();
+        let cx = &mut Context::from_waker(&waker);
+        loop {
+            self.check()?;
+            if self.channel.is_sent() || self.stream.is_closed() {
+                return Poll::Ready(Ok(()));
+            }
+            ready!(self.stream.poll_reconnect(cx))?;
+            // connection may be new one
+            self.check()?;
+            let output = self.channel.output();
+            match ready!(self.stream.poll_write_connected(cx, output)) {
+                Ok(n) => self.channel.advance_output(n),
+                Err(err) if is_reconnecting(&err) => {}
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    fn poll_recv(&mut self) -> Poll<Result<(), Error>> {
+        let waker = self.waker.clone();
+        let cx = &mut Context::from_waker(&waker);
+        let mut buf = [0; 8 * 1024];
+        self.check()?;
+        let mut read_buf = ReadBuf::new(&mut buf);
+        let res = Pin::new(&mut self.stream).poll_read(cx, &mut read_buf);
+        // bytes of new connection follow its HELLO, which may need sending
+        let resumed = self.check()?;
+        if resumed && res.is_pending() {
+            return Poll::Ready(Ok(()));
+        }
+        match ready!(res) {
+            Ok(()) if read_buf.filled().is_empty() => Poll::Ready(Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed before end of session",
+            ))),
+            Ok(()) => {
+                let res = self.channel.feed(read_buf.filled());
+                self.fail(res)?;
+                self.resume.lock().unwrap().received = self.channel.received();
+                Poll::Ready(Ok(()))
+            }
+            Err(err) if is_reconnecting(&err) => yield_now(cx),
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+impl<C: Connector> AsyncRead for Session<C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        self.get_mut().poll_read_data(cx, buf)
+    }
+}
+
+impl<C: Connector> AsyncWrite for Session<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        self.get_mut().poll_write_data(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        Wakers::register(&this.wakers.write, cx.waker());
+        let waker = this.waker.clone();
+        loop {
+            ready!(this.poll_send())?;
+            if this.stream.is_closed() {
+                return Poll::Ready(Ok(()));
+            }
+            let res = Pin::new(&mut this.stream).poll_flush(&mut Context::from_waker(&waker));
+            match ready!(res) {
+                Ok(()) => {
+                    // flush may reconnect, then everything is sent again
+                    this.check()?;
+                    if this.channel.is_sent() {
+                        return Poll::Ready(Ok(()));
+                    }
+                }
+                Err(err) if is_reconnecting(&err) => {}
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    /// Send end of data and wait until peer reads all of it.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.get_mut().poll_close(cx)
+    }
+}
@@ -0,0 +1,348 @@
+// Applying TcpStreamSettings to sockets.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{Error, ErrorKind};
+use tokio::net::{TcpSocket, TcpStream};
+
+use crate::tcp::TcpStreamSettings;
+
+// Create socket with all settings applied and connect it to `addr`.
+pub(crate) async fn connect(
+    addr: SocketAddr,
+    settings: &TcpStreamSettings,
+) -> Result<TcpStream, Error> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    apply(settings, SockRef::from(&socket), addr.is_ipv6())?;
+    if let Some(bind_addr) = settings.bind_addr {
+        socket.bind(bind_addr)?;
+    }
+    #[cfg(feature = "tracing")]
+    tracing::debug!(%addr, ?settings, "socket options applied");
+    let ts = socket.connect(addr).await?;
+    apply_after_connect(settings, SockRef::from(&ts))?;
+    Ok(ts)
+}
+
+// Apply every option except bind address.
+pub(crate) fn apply(
+    settings: &TcpStreamSettings,
+    sock: SockRef<'_>,
+    ipv6: bool,
+) -> Result<(), Error> {
+    sock.set_nodelay(settings.nodelay)?;
+    set_keepalive(settings, &sock)?;
+    if let Some(size) = settings.send_buffer_size {
+        sock.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = settings.recv_buffer_size {
+        sock.set_recv_buffer_size(size)?;
+    }
+    if let Some(ttl) = settings.ttl {
+        if ipv6 {
+            sock.set_unicast_hops_v6(ttl)?;
+        } else {
+            sock.set_ttl(ttl)?;
+        }
+    }
+    if let Some(linger) = settings.linger {
+        sock.set_linger(Some(linger))?;
+    }
+    if let Some(tos) = settings.tos {
+        set_tos(&sock, tos, ipv6)?;
+    }
+    set_linux_only(settings, &sock)?;
+    apply_after_connect(settings, sock)
+}
+
+// TCP_QUICKACK is not permanent, it has to be set on connected socket.
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn apply_after_connect(settings: &TcpStreamSettings, sock: SockRef<'_>) -> Result<(), Error> {
+    if let Some(quickack) = settings.quickack {
+        sock.set_quickack(quickack)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn apply_after_connect(settings: &TcpStreamSettings, _sock: SockRef<'_>) -> Result<(), Error> {
+    if settings.quickack.is_some() {
+        return Err(unsupported("quickack"));
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn set_linux_only(settings: &TcpStreamSettings, sock: &SockRef<'_>) -> Result<(), Error> {
+    if let Some(timeout) = settings.user_timeout {
+        sock.set_tcp_user_timeout(Some(timeout))?;
+    }
+    if let Some(mark) = settings.mark {
+        sock.set_mark(mark)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn set_linux_only(settings: &TcpStreamSettings, _sock: &SockRef<'_>) -> Result<(), Error> {
+    if settings.user_timeout.is_some() {
+        return Err(unsupported("user_timeout"));
+    }
+    if settings.mark.is_some() {
+        return Err(unsupported("mark"));
+    }
+    Ok(())
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+fn set_tos(sock: &SockRef<'_>, tos: u32, ipv6: bool) -> Result<(), Error> {
+    if ipv6 {
+        sock.set_tclass_v6(tos)
+    } else {
+        sock.set_tos(tos)
+    }
+}
+
+#[cfg(all(
+    not(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "fuchsia",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )),
+    not(any(target_os = "redox", target_os = "solaris", target_os = "illumos"))
+))]
+fn set_tos(sock: &SockRef<'_>, tos: u32, ipv6: bool) -> Result<(), Error> {
+    if ipv6 {
+        Err(unsupported("tos for IPv6"))
+    } else {
+        sock.set_tos(tos)
+    }
+}
+
+#[cfg(any(target_os = "redox", target_os = "solaris", target_os = "illumos"))]
+fn set_tos(_sock: &SockRef<'_>, _tos: u32, _ipv6: bool) -> Result<(), Error> {
+    Err(unsupported("tos"))
+}
+
+fn set_keepalive(settings: &TcpStreamSettings, sock: &SockRef<'_>) -> Result<(), Error> {
+    match settings.keepalive {
+        Some(time) => {
+            let params = keepalive_params(settings, TcpKeepalive::new().with_time(time))?;
+            sock.set_tcp_keepalive(&params)
+        }
+        None => sock.set_keepalive(false),
+    }
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd"
+))]
+fn keepalive_params(
+    settings: &TcpStreamSettings,
+    mut params: TcpKeepalive,
+) -> Result<TcpKeepalive, Error> {
+    if let Some(interval) = settings.keepalive_interval {
+        params = params.with_interval(interval);
+    }
+    if let Some(retries) = settings.keepalive_retries {
+        params = params.with_retries(retries);
+    }
+    Ok(params)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd"
+)))]
+fn keepalive_params(
+    settings: &TcpStreamSettings,
+    params: TcpKeepalive,
+) -> Result<TcpKeepalive, Error> {
+    if settings.keepalive_interval.is_some() || settings.keepalive_retries.is_some() {
+        return Err(unsupported("keepalive_interval and keepalive_retries"));
+    }
+    Ok(params)
+}
+
+// Read options back from connected socket. Fields that are not socket options are copied from
+// `settings`.
+pub(crate) fn read(
+    ts: &TcpStream,
+    settings: &TcpStreamSettings,
+) -> Result<TcpStreamSettings, Error> {
+    let sock = SockRef::from(ts);
+    let ipv6 = ts.local_addr()?.is_ipv6();
+    let mut live = TcpStreamSettings {
+        nodelay: sock.nodelay()?,
+        keepalive: get_keepalive(ts)?,
+        keepalive_interval: None,
+        keepalive_retries: None,
+        send_buffer_size: Some(sock.send_buffer_size()?),
+        recv_buffer_size: Some(sock.recv_buffer_size()?),
+        ttl: Some(if ipv6 {
+            sock.unicast_hops_v6()?
+        } else {
+            sock.ttl()?
+        }),
+        linger: sock.linger()?,
+        tos: read_tos(&sock, ipv6)?,
+        ..settings.clone()
+    };
+    if live.keepalive.is_some() {
+        read_keepalive_params(&sock, &mut live)?;
+    }
+    read_linux_only(&sock, &mut live)?;
+    Ok(live)
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn read_linux_only(sock: &SockRef<'_>, live: &mut TcpStreamSettings) -> Result<(), Error> {
+    live.user_timeout = sock.tcp_user_timeout()?;
+    live.mark = Some(sock.mark()?);
+    live.quickack = Some(sock.quickack()?);
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn read_linux_only(_sock: &SockRef<'_>, live: &mut TcpStreamSettings) -> Result<(), Error> {
+    live.user_timeout = None;
+    live.mark = None;
+    live.quickack = None;
+    Ok(())
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+fn read_tos(sock: &SockRef<'_>, ipv6: bool) -> Result<Option<u32>, Error> {
+    if ipv6 {
+        sock.tclass_v6().map(Some)
+    } else {
+        sock.tos().map(Some)
+    }
+}
+
+#[cfg(all(
+    not(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "fuchsia",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )),
+    not(any(target_os = "redox", target_os = "solaris", target_os = "illumos"))
+))]
+fn read_tos(sock: &SockRef<'_>, ipv6: bool) -> Result<Option<u32>, Error> {
+    if ipv6 {
+        Ok(None)
+    } else {
+        sock.tos().map(Some)
+    }
+}
+
+#[cfg(any(target_os = "redox", target_os = "solaris", target_os = "illumos"))]
+fn read_tos(_sock: &SockRef<'_>, _ipv6: bool) -> Result<Option<u32>, Error> {
+    Ok(None)
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd"
+))]
+fn read_keepalive_params(sock: &SockRef<'_>, live: &mut TcpStreamSettings) -> Result<(), Error> {
+    live.keepalive_interval = Some(sock.keepalive_interval()?);
+    live.keepalive_retries = Some(sock.keepalive_retries()?);
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd"
+)))]
+fn read_keepalive_params(_sock: &SockRef<'_>, _live: &mut TcpStreamSettings) -> Result<(), Error> {
+    Ok(())
+}
+
+pub(crate) fn get_keepalive(ts: &TcpStream) -> Result<Option<Duration>, Error> {
+    let sock = SockRef::from(ts);
+    if sock.keepalive()? {
+        Ok(Some(sock.keepalive_time()?))
+    } else {
+        Ok(None)
+    }
+}
+
+// Bytes in send queue not acknowledged by peer (SIOCOUTQ, same request as TIOCOUTQ)
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) fn unacked_bytes(ts: &TcpStream) -> Option<usize> {
+    use std::convert::TryFrom;
+    use std::os::unix::io::AsRawFd;
+
+    let mut queued: libc::c_int = 0;
+    // SAFETY: fd is owned by `ts` and valid for the call, TIOCOUTQ writes single c_int
+    let res = unsafe { libc::ioctl(ts.as_raw_fd(), libc::TIOCOUTQ, &mut queued) };
+    if res < 0 {
+        return None;
+    }
+    usize::try_from(queued).ok()
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+pub(crate) fn unacked_bytes(_ts: &TcpStream) -> Option<usize> {
+    None
+}
+
+// every option is supported on Linux
+#[allow(dead_code)]
+fn unsupported(option: &str) -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        format!("{} is not supported on this platform", option),
+    )
+}
@@ -0,0 +1,333 @@
+//! Write queue kept in file, surviving outages longer than memory allows and process restarts.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use tokio::io::{Error, ErrorKind};
+
+use crate::queue::QueuePolicy;
+
+// File starts with magic and offset of first byte not written to connection, data follows
+const MAGIC: &[u8; 4] = b"RTSQ";
+const HEADER_LEN: u64 = 12;
+// how much is read from file at once
+const READ_CHUNK: usize = 64 * 1024;
+// file is rewritten when that many consumed bytes precede the data, and at least as many as
+// there are left
+const COMPACT_MIN: u64 = 1024 * 1024;
+
+/// When [Spool] changes are flushed to disk with `fsync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SyncPolicy {
+    /// After every change. Survives power failure, slowest.
+    Always,
+    /// On change, when last flush is older than interval.
+    Interval(Duration),
+    /// Left to OS. Survives process crash, but not power failure.
+    Never,
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        SyncPolicy::Interval(Duration::from_secs(1))
+    }
+}
+
+/// Write queue stored in append-only file, set with
+/// [set_spool](crate::RetryingStream::set_spool).
+///
+/// Bytes written while stream is connecting are appended to the file and written to the new
+/// connection in order, like with [set_write_queue](crate::RetryingStream::set_write_queue).
+/// Data left in the file is kept when stream is dropped or shut down and is written first by the
+/// next stream using the file, e.g. after restart. Position of written data is stored after it
+/// was written to connection, so bytes written just before crash can be sent twice.
+///
+/// File is accessed with blocking calls from `poll_*()` methods. Once everything was written it
+/// is truncated. Dropped bytes stay in the file until it holds twice as many as it should, at
+/// least 1 MiB, then data is moved to new file.
+pub struct Spool {
+    path: PathBuf,
+    file: File,
+    policy: QueuePolicy,
+    sync: SyncPolicy,
+    last_sync: Instant,
+    // file offset of first byte not written to connection
+    read_pos: u64,
+    end: u64,
+    // bytes after `read_pos` already read from file
+    front: Vec<u8>,
+}
+
+impl Spool {
+    /// Open spool at `path`, creating the file if it doesn't exist. At most `policy.capacity`
+    /// bytes are kept, `policy.overflow` tells what happens with more.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        policy: QueuePolicy,
+        sync: SyncPolicy,
+    ) -> Result<Self, Error> {
+        if policy.capacity == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "spool capacity is zero",
+            ));
+        }
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut spool = Self {
+            path,
+            file,
+            policy,
+            sync,
+            last_sync: Instant::now(),
+            read_pos: HEADER_LEN,
+            end: HEADER_LEN,
+            front: Vec::new(),
+        };
+        let len = spool.file.metadata()?.len();
+        if len < HEADER_LEN {
+            // new file, or header was never completely written
+            spool.file.set_len(0)?;
+            spool.write_header()?;
+        } else {
+            let mut header = [0; HEADER_LEN as usize];
+            spool.file.seek(SeekFrom::Start(0))?;
+            spool.file.read_exact(&mut header)?;
+            if &header[..4] != MAGIC {
+                return Err(Error::new(ErrorKind::InvalidData, "not a spool file"));
+            }
+            let mut read_pos = [0; 8];
+            read_pos.copy_from_slice(&header[4..]);
+            // file is truncated before header is updated
+            spool.read_pos = u64::from_be_bytes(read_pos).clamp(HEADER_LEN, len);
+            spool.end = len;
+        }
+        Ok(spool)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn policy(&self) -> &QueuePolicy {
+        &self.policy
+    }
+
+    /// Number of bytes not written to connection yet.
+    pub fn len(&self) -> u64 {
+        self.end - self.read_pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn append(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(data)?;
+        self.end += data.len() as u64;
+        self.sync()
+    }
+
+    // Oldest bytes not written to connection, empty when there are none
+    pub(crate) fn front(&mut self) -> Result<&[u8], Error> {
+        if self.front.is_empty() && self.read_pos < self.end {
+            let len = (self.end - self.read_pos).min(READ_CHUNK as u64) as usize;
+            self.front.resize(len, 0);
+            self.file.seek(SeekFrom::Start(self.read_pos))?;
+            self.file.read_exact(&mut self.front)?;
+        }
+        Ok(&self.front)
+    }
+
+    // Forget `n` oldest bytes, they were written to connection or dropped
+    pub(crate) fn consume(&mut self, n: usize) -> Result<(), Error> {
+        if n == 0 {
+            return Ok(());
+        }
+        self.read_pos = (self.read_pos + n as u64).min(self.end);
+        self.front.drain(..n.min(self.front.len()));
+        if self.read_pos == self.end {
+            self.file.set_len(HEADER_LEN)?;
+            self.read_pos = HEADER_LEN;
+            self.end = HEADER_LEN;
+        }
+        let consumed = self.read_pos - HEADER_LEN;
+        if consumed >= COMPACT_MIN && consumed >= self.len() {
+            return self.compact();
+        }
+        self.write_header()
+    }
+
+    fn write_header(&mut self) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header(self.read_pos))?;
+        self.sync()
+    }
+
+    // Move data to new file without consumed bytes. Old file is replaced only when new one is
+    // complete.
+    fn compact(&mut self) -> Result<(), Error> {
+        let mut tmp_path = OsString::from(&self.path);
+        tmp_path.push(".tmp");
+        let mut tmp = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp.write_all(&header(HEADER_LEN))?;
+        let len = self.len();
+        self.file.seek(SeekFrom::Start(self.read_pos))?;
+        io::copy(&mut (&mut self.file).take(len), &mut tmp)?;
+        if self.sync != SyncPolicy::Never {
+            tmp.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = tmp;
+        self.read_pos = HEADER_LEN;
+        self.end = HEADER_LEN + len;
+        Ok(())
+    }
+
+    // Flush to disk according to sync policy
+    fn sync(&mut self) -> Result<(), Error> {
+        let due = match self.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+        if due {
+            self.file.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+fn header(read_pos: u64) -> [u8; HEADER_LEN as usize] {
+    let mut header = [0; HEADER_LEN as usize];
+    header[..4].copy_from_slice(MAGIC);
+    header[4..].copy_from_slice(&read_pos.to_be_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::tests::drain;
+    use crate::queue::{QueueOverflow, WriteQueue};
+
+    fn open(path: &Path) -> Spool {
+        let policy = QueuePolicy::new(16 * 1024 * 1024, QueueOverflow::Block);
+        Spool::open(path, policy, SyncPolicy::Never).unwrap()
+    }
+
+    fn drain_spool(spool: Spool) -> Vec<u8> {
+        drain(&mut WriteQueue::with_spool(spool))
+    }
+
+    #[test]
+    fn unread_data_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spool");
+        let mut spool = open(&path);
+        spool.append(b"hello ").unwrap();
+        spool.append(b"world").unwrap();
+        assert_eq!(&spool.front().unwrap()[..3], b"hel");
+        spool.consume(3).unwrap();
+        drop(spool);
+
+        let spool = open(&path);
+        assert_eq!(spool.len(), 8);
+        assert_eq!(drain_spool(spool), b"lo world");
+
+        let mut spool = open(&path);
+        assert!(spool.is_empty());
+        assert_eq!(fs::metadata(&path).unwrap().len(), HEADER_LEN);
+        spool.append(b"again").unwrap();
+        assert_eq!(drain_spool(spool), b"again");
+    }
+
+    #[test]
+    fn read_pos_is_clamped_to_truncated_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spool");
+        let mut spool = open(&path);
+        spool.append(b"0123456789").unwrap();
+        spool.consume(4).unwrap();
+        drop(spool);
+        // crash after truncating the file, before header was updated
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(HEADER_LEN + 2)
+            .unwrap();
+
+        let mut spool = open(&path);
+        assert!(spool.is_empty());
+        assert!(spool.front().unwrap().is_empty());
+        spool.append(b"next").unwrap();
+        assert_eq!(drain_spool(spool), b"next");
+    }
+
+    #[test]
+    fn short_header_is_rewritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spool");
+        fs::write(&path, b"RTS").unwrap();
+        let mut spool = open(&path);
+        assert!(spool.is_empty());
+        spool.append(b"data").unwrap();
+        drop(spool);
+        assert_eq!(drain_spool(open(&path)), b"data");
+    }
+
+    #[test]
+    fn foreign_file_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spool");
+        fs::write(&path, b"not a spool file").unwrap();
+        let policy = QueuePolicy::new(1024, QueueOverflow::Block);
+        let err = Spool::open(&path, policy, SyncPolicy::Never).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn compacts_after_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spool");
+        let mut spool = open(&path);
+        let data: Vec<u8> = (0..COMPACT_MIN as usize + 1000).map(|i| i as u8).collect();
+        spool.append(&data).unwrap();
+        spool.append(b"tail").unwrap();
+        let file_len = || fs::metadata(&path).unwrap().len();
+
+        // below threshold consumed bytes stay in the file
+        spool.consume(COMPACT_MIN as usize - 1).unwrap();
+        assert_eq!(file_len(), HEADER_LEN + data.len() as u64 + 4);
+        spool.consume(1).unwrap();
+        assert_eq!(file_len(), HEADER_LEN + 1000 + 4);
+        assert_eq!(spool.len(), 1004);
+
+        let mut expected = data[COMPACT_MIN as usize..].to_vec();
+        expected.extend_from_slice(b"tail");
+        drop(spool);
+        // compacted file is complete, temporary one is gone
+        assert!(!dir.path().join("spool.tmp").exists());
+        assert_eq!(drain_spool(open(&path)), expected);
+    }
+}
@@ -0,0 +1,108 @@
+//! Counters describing connection history of [RetryingStream](crate::RetryingStream).
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::io::{Error, ErrorKind};
+
+/// Snapshot returned by [stats](crate::RetryingStream::stats).
+///
+/// Counters start at stream creation. With `serde` feature it implements `Serialize`; error
+/// kinds are serialized as their `Debug` names.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct Stats {
+    /// Connect attempts started.
+    pub connect_attempts: u64,
+    /// Attempts that ended connected, including handshake.
+    pub connects: u64,
+    /// Attempts that failed.
+    pub connect_failures: u64,
+    /// Established connections that were dropped because of error.
+    pub disconnects: u64,
+    /// Connect failures and disconnects by error kind.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serde_kinds::map"))]
+    pub failures_by_kind: HashMap<ErrorKind, u64>,
+    /// Bytes read from all connections.
+    pub bytes_read: u64,
+    /// Bytes written to all connections.
+    pub bytes_written: u64,
+    /// Bytes read from current or last connection.
+    pub connection_bytes_read: u64,
+    /// Bytes written to current or last connection.
+    pub connection_bytes_written: u64,
+    /// How long current connection is up. `None` when not connected.
+    pub connection_age: Option<Duration>,
+    /// Kind of error that dropped last established connection.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serde_kinds::option"))]
+    pub last_disconnect_kind: Option<ErrorKind>,
+    /// Message of error that dropped last established connection.
+    pub last_disconnect_reason: Option<String>,
+    /// Bytes waiting in write queue.
+    pub queued_bytes: u64,
+    /// Bytes write queue dropped because it was full.
+    pub queue_dropped_bytes: u64,
+}
+
+impl Stats {
+    pub(crate) fn on_connect(&mut self) {
+        self.connect_attempts += 1;
+    }
+
+    pub(crate) fn on_connected(&mut self) {
+        self.connects += 1;
+        self.connection_bytes_read = 0;
+        self.connection_bytes_written = 0;
+    }
+
+    pub(crate) fn on_failure(&mut self, err: &Error, was_connected: bool) {
+        *self.failures_by_kind.entry(err.kind()).or_insert(0) += 1;
+        if was_connected {
+            self.disconnects += 1;
+            self.last_disconnect_kind = Some(err.kind());
+            self.last_disconnect_reason = Some(err.to_string());
+        } else {
+            self.connect_failures += 1;
+        }
+    }
+
+    pub(crate) fn on_read(&mut self, n: usize) {
+        self.bytes_read += n as u64;
+        self.connection_bytes_read += n as u64;
+    }
+
+    pub(crate) fn on_write(&mut self, n: usize) {
+        self.bytes_written += n as u64;
+        self.connection_bytes_written += n as u64;
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_kinds {
+    use std::collections::HashMap;
+
+    use serde::ser::{SerializeMap, Serializer};
+    use tokio::io::ErrorKind;
+
+    pub(super) fn map<S: Serializer>(
+        kinds: &HashMap<ErrorKind, u64>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(kinds.len()))?;
+        for (kind, count) in kinds {
+            map.serialize_entry(&format!("{:?}", kind), count)?;
+        }
+        map.end()
+    }
+
+    pub(super) fn option<S: Serializer>(
+        kind: &Option<ErrorKind>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match kind {
+            Some(kind) => serializer.serialize_some(&format!("{:?}", kind)),
+            None => serializer.serialize_none(),
+        }
+    }
+}
@@ -0,0 +1,1680 @@
+//! Generic [RetryingStream] over any [Connector].
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite, Error, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::classify::{DefaultClassifier, EofPolicy, ErrorAction, ErrorClassifier};
+use crate::connector::Connector;
+use crate::error::{is_reconnecting, ErrorCategory, RetryingError};
+use crate::event::{clone_error, Event, EventStream, Observers};
+use crate::handshake::{Handshake, HandshakeFuture};
+use crate::policy::{ConstantBackoff, ReconnectPolicy};
+use crate::queue::{QueueOverflow, QueuePolicy, WriteQueue};
+use crate::replay::{Overflow, ReplayBuffer, ReplayPolicy, REPLAY_POLL_INTERVAL};
+use crate::spool::Spool;
+use crate::stats::Stats;
+use crate::targets::{Strategy, TargetSet};
+use crate::telemetry::Telemetry;
+use crate::DEFAULT_STABLE_PERIOD;
+
+// Handle connection state
+pub(crate) enum ConnectionState<C: Connector> {
+    // first connect is started on first poll
+    Idle,
+    Backoff(Pin<Box<Sleep>>),
+    ConnectFuture(Pin<Box<C::Future>>),
+    Handshake(HandshakeFuture<C::Transport>),
+    Connected(C::Transport),
+    // poll_shutdown was called, waiting for transport to finish it
+    ShuttingDown(C::Transport),
+    // terminal state, transport is kept so rest of data can be read
+    Closed(Option<C::Transport>),
+    // reconnect limit reached, left only by revive()
+    GaveUp(RetryingError),
+}
+
+/// [Event] emitted by [RetryingStream] using connector `C`.
+pub type StreamEvent<C> = Event<<C as Connector>::Target, <C as Connector>::Addr>;
+
+/// Like [Connector::Transport] but pollable after Error.
+///
+/// Any error returned by transport resets inner state and next `poll*()` call will reconnect with
+/// [Connector].
+pub struct RetryingStream<C: Connector> {
+    pub(crate) connector: C,
+    pub(crate) targets: TargetSet<C::Target>,
+    pub(crate) state: ConnectionState<C>,
+    pub(crate) policy: Box<dyn ReconnectPolicy>,
+    pub(crate) stable_period: Duration,
+    // number of reconnects since connection was last stable
+    attempt: u32,
+    // when current transport was established
+    connected_at: Option<Instant>,
+    // wake up when it is time to go back to primary target
+    failback_timer: Option<Pin<Box<Sleep>>>,
+    // connect to primary target made while connected to backup one
+    failback_probe: Option<Pin<Box<C::Future>>>,
+    pub(crate) observers: Observers<C::Target, C::Addr>,
+    pub(crate) handshake: Option<Box<dyn Handshake<C::Transport>>>,
+    pub(crate) classifier: Box<dyn ErrorClassifier>,
+    pub(crate) eof_policy: EofPolicy,
+    pub(crate) max_attempts: Option<u32>,
+    pub(crate) give_up_after: Option<Duration>,
+    // consecutive failed connects
+    failed_connects: u32,
+    // when connection was lost or first connect failed
+    outage_since: Option<Instant>,
+    // wake up when there was no connection for give_up_after
+    give_up_timer: Option<Pin<Box<Sleep>>>,
+    telemetry: Telemetry,
+    replay: Option<ReplayBuffer>,
+    // wake up to check if peer acknowledged bytes filling replay buffer
+    replay_timer: Option<Pin<Box<Sleep>>>,
+    queue: Option<WriteQueue>,
+    // number of last lost connection and its unacknowledged bytes
+    lost_unacked: (u64, Option<usize>),
+}
+
+// Nothing is structurally pinned: connect future is boxed and transport is Unpin.
+impl<C: Connector> Unpin for RetryingStream<C> {}
+
+/// Implement creators
+impl<C: Connector> RetryingStream<C> {
+    /// Create stream that will connect to `target`. Connection is started on first poll.
+    pub fn new(connector: C, target: C::Target) -> Self {
+        Self::with_target_set(connector, TargetSet::single(target))
+    }
+
+    /// Create stream with many targets. [Strategy] decide which one is used after reset.
+    ///
+    /// First target is the primary one and is used first. Return `InvalidInput` when `targets`
+    /// is empty or don't match the strategy.
+    pub fn with_targets(
+        connector: C,
+        targets: Vec<C::Target>,
+        strategy: Strategy,
+    ) -> Result<Self, Error> {
+        Ok(Self::with_target_set(
+            connector,
+            TargetSet::new(targets, strategy)?,
+        ))
+    }
+
+    /// Create stream that will connect to current target of `targets`. Connection is started on
+    /// first poll.
+    pub fn with_target_set(connector: C, targets: TargetSet<C::Target>) -> Self {
+        Self::with_state(connector, targets, ConnectionState::Idle)
+    }
+
+    /// Create stream from already connected transport.
+    ///
+    /// [Connector::configure] is not called for `transport`.
+    pub fn from_transport(connector: C, target: C::Target, transport: C::Transport) -> Self {
+        let addrs = connector.addrs(&transport).ok();
+        let mut stream = Self::with_state(
+            connector,
+            TargetSet::single(target),
+            ConnectionState::Connected(transport),
+        );
+        stream.connected_at = Some(Instant::now());
+        let addrs = addrs.as_ref().map(|(local, peer)| (local, peer));
+        stream.telemetry.on_adopted(stream.targets.current(), addrs);
+        stream
+    }
+
+    fn with_state(connector: C, targets: TargetSet<C::Target>, state: ConnectionState<C>) -> Self {
+        Self {
+            connector,
+            targets,
+            state,
+            policy: Box::new(ConstantBackoff::default()),
+            stable_period: DEFAULT_STABLE_PERIOD,
+            attempt: 0,
+            connected_at: None,
+            failback_timer: None,
+            failback_probe: None,
+            observers: Observers::new(),
+            handshake: None,
+            classifier: Box::new(DefaultClassifier),
+            eof_policy: EofPolicy::Return,
+            max_attempts: None,
+            give_up_after: None,
+            failed_connects: 0,
+            outage_since: None,
+            give_up_timer: None,
+            telemetry: Telemetry::new(),
+            replay: None,
+            replay_timer: None,
+            queue: None,
+            lost_unacked: (0, None),
+        }
+    }
+}
+
+/// Implement additional methods
+impl<C: Connector> RetryingStream<C> {
+    /// Set policy used to delay reconnect attempts.
+    pub fn set_reconnect_policy<P: ReconnectPolicy + 'static>(&mut self, policy: P) {
+        self.policy = Box::new(policy);
+        self.attempt = 0;
+    }
+
+    /// Set how long connection has to stay up before reconnect policy is reset.
+    ///
+    /// Default is [DEFAULT_STABLE_PERIOD].
+    pub fn set_stable_period(&mut self, stable_period: Duration) {
+        self.stable_period = stable_period;
+    }
+
+    /// Give up after `max_attempts` consecutive failed connects. Default is to never give up.
+    ///
+    /// See [revive](RetryingStream::revive).
+    pub fn set_max_attempts(&mut self, max_attempts: Option<u32>) {
+        self.max_attempts = max_attempts;
+    }
+
+    /// Give up when there is no connection for `give_up_after`, counted from first failed connect
+    /// or lost connection. Pending backoff or connect is cancelled when time is up. Default is to
+    /// never give up.
+    ///
+    /// See [revive](RetryingStream::revive).
+    pub fn set_give_up_after(&mut self, give_up_after: Option<Duration>) {
+        self.give_up_after = give_up_after;
+        self.give_up_timer = None;
+    }
+
+    /// return true when reconnect limit was reached.
+    ///
+    /// Every `poll*()` method then returns [RetryingError] with [ErrorCategory::GaveUp].
+    pub fn has_given_up(&self) -> bool {
+        matches!(self.state, ConnectionState::GaveUp(_))
+    }
+
+    /// Start reconnecting again after stream gave up. Limits count from zero.
+    ///
+    /// Do nothing in other states.
+    pub fn revive(&mut self) {
+        if self.has_given_up() {
+            debug!("RetryingStream => revive");
+            self.state = ConnectionState::Idle;
+            self.attempt = 0;
+            self.failed_connects = 0;
+            self.outage_since = None;
+            self.give_up_timer = None;
+            self.policy.reset();
+        }
+    }
+
+    /// Retain recently written bytes and write those peer didn't acknowledge to next connection,
+    /// before any new data. `None` disables it, which is the default.
+    ///
+    /// Unacknowledged bytes are counted with [Connector::unacked_bytes] when connection is lost.
+    /// When connector can't tell, all retained bytes are written again, so peer may receive some
+    /// of them twice. Bytes are resent as soon as new connection is established, by whichever
+    /// `poll*()` method established it. Already retained bytes are dropped.
+    pub fn set_replay_policy(&mut self, policy: Option<ReplayPolicy>) {
+        self.replay = policy.map(ReplayBuffer::new);
+        self.replay_timer = None;
+    }
+
+    /// Accept writes while stream is connecting and write them to the new connection, after
+    /// replayed bytes and before any new data. `None` disables it, which is the default.
+    ///
+    /// Failed connects are then not returned from `poll_write()` and `poll_flush()`, they wait
+    /// for connection; errors that stop reconnecting still are. Queued bytes are written as soon
+    /// as new connection is established, by whichever `poll*()` method established it, and before
+    /// shutdown of connected stream. Shutdown while not connected drops them, as does changing
+    /// the policy.
+    pub fn set_write_queue(&mut self, policy: Option<QueuePolicy>) {
+        self.queue = policy.map(WriteQueue::new);
+    }
+
+    /// Use `spool` as write queue, see [set_write_queue](RetryingStream::set_write_queue).
+    /// `None` disables it; data left in spool file is kept.
+    pub fn set_spool(&mut self, spool: Option<Spool>) {
+        self.queue = spool.map(WriteQueue::with_spool);
+    }
+
+    /// Number of bytes waiting in write queue.
+    pub fn queued_bytes(&self) -> usize {
+        self.queue.as_ref().map_or(0, WriteQueue::len)
+    }
+
+    /// Set name identifying this stream in metrics labels.
+    pub fn set_name<S: Into<String>>(&mut self, name: S) {
+        self.telemetry.set_name(name.into());
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.telemetry.name.as_deref()
+    }
+
+    /// Return snapshot of connection counters.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            connection_age: self.connected_at.map(|at| at.elapsed()),
+            queued_bytes: self.queued_bytes() as u64,
+            queue_dropped_bytes: self.queue.as_ref().map_or(0, WriteQueue::dropped),
+            ..self.telemetry.stats.clone()
+        }
+    }
+
+    // Number of established connections, changes when there is new one
+    pub(crate) fn connects(&self) -> u64 {
+        self.telemetry.stats.connects
+    }
+
+    /// return true if RetryingStream holds connected transport at this moment.
+    ///
+    /// This can change after calling any `poll*()` method when that function returned error.
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, ConnectionState::Connected(_))
+    }
+
+    /// return true after [poll_shutdown](AsyncWrite::poll_shutdown) was called.
+    ///
+    /// Closed stream never reconnects.
+    pub fn is_closed(&self) -> bool {
+        matches!(
+            self.state,
+            ConnectionState::ShuttingDown(_) | ConnectionState::Closed(_)
+        )
+    }
+
+    /// Run `handshake` on every new connection before it is used. See [Handshake].
+    pub fn set_handshake<H: Handshake<C::Transport> + 'static>(&mut self, handshake: H) {
+        self.handshake = Some(Box::new(handshake));
+    }
+
+    /// Set what I/O errors reset the connection. Default is [DefaultClassifier].
+    pub fn set_error_classifier<E: ErrorClassifier + 'static>(&mut self, classifier: E) {
+        self.classifier = Box::new(classifier);
+    }
+
+    /// Set what happens when peer closes the connection. Default is [EofPolicy::Return].
+    pub fn set_eof_policy(&mut self, eof_policy: EofPolicy) {
+        self.eof_policy = eof_policy;
+    }
+
+    /// Call `callback` on every [Event].
+    ///
+    /// `callback` is called immediately with event describing current state, e.g.
+    /// [Event::Connected] for stream created from connected transport.
+    pub fn on_event<F>(&mut self, callback: F)
+    where
+        F: FnMut(&StreamEvent<C>) + Send + 'static,
+    {
+        let current = self.current_event();
+        self.observers.add_callback(Box::new(callback), current);
+    }
+
+    /// Return [Stream](futures::Stream) of events.
+    ///
+    /// First item describes current state, like in [on_event](RetryingStream::on_event). Events
+    /// are buffered until read.
+    pub fn subscribe(&mut self) -> EventStream<C::Target, C::Addr> {
+        let current = self.current_event();
+        self.observers.subscribe(current)
+    }
+
+    /// Target currently in use or, when not connected, target of next connect attempt.
+    pub fn target(&self) -> &C::Target {
+        self.targets.current()
+    }
+
+    /// All targets with strategy selecting current one.
+    pub fn targets(&self) -> &TargetSet<C::Target> {
+        &self.targets
+    }
+
+    /// See [TargetSet::set_failback_after].
+    pub fn set_failback_after(&mut self, failback_after: Option<Duration>) {
+        self.targets.set_failback_after(failback_after);
+        self.failback_timer = None;
+        self.failback_probe = None;
+    }
+
+    pub fn connector(&self) -> &C {
+        &self.connector
+    }
+
+    /// Changes made here are used from next connect. Already connected transport is not touched.
+    pub fn connector_mut(&mut self) -> &mut C {
+        &mut self.connector
+    }
+
+    /// Return connected transport or `NotConnected` error.
+    pub fn get_ref(&self) -> Result<&C::Transport, Error> {
+        match &self.state {
+            ConnectionState::Connected(t) => Ok(t),
+            _ => Err(self.not_connected_error()),
+        }
+    }
+
+    // Error for operation that needs connected transport
+    pub(crate) fn not_connected_error(&self) -> Error {
+        match &self.state {
+            ConnectionState::ShuttingDown(_) | ConnectionState::Closed(_) => {
+                self.error(ErrorCategory::Closed, None).into()
+            }
+            ConnectionState::GaveUp(err) => err.clone().into(),
+            _ => self.error(ErrorCategory::NotConnected, None).into(),
+        }
+    }
+
+    fn error(&self, category: ErrorCategory, source: Option<Error>) -> RetryingError {
+        RetryingError::new(
+            category,
+            self.attempt,
+            self.targets.current().to_string(),
+            source.map(Arc::new),
+        )
+    }
+
+    // Describe `err` with current attempt and target, then reset
+    fn fail(&mut self, category: ErrorCategory, err: Error) -> Error {
+        let err = self.error(category, Some(err));
+        if let Some(source) = err.io_error() {
+            self.reset(source);
+        }
+        err.into()
+    }
+
+    fn current_event(&self) -> Option<StreamEvent<C>> {
+        match &self.state {
+            ConnectionState::Idle | ConnectionState::ShuttingDown(_) => None,
+            ConnectionState::Closed(_) => Some(Event::Closed),
+            ConnectionState::GaveUp(err) => Some(Event::GaveUp {
+                attempts: err.attempt(),
+                error: Arc::new(clone_error(err.io_error()?)),
+            }),
+            ConnectionState::Backoff(delay) => Some(Event::BackingOff {
+                delay: delay.deadline().duration_since(tokio::time::Instant::now()),
+            }),
+            ConnectionState::ConnectFuture(_) | ConnectionState::Handshake(_) => {
+                Some(Event::Connecting {
+                    attempt: self.attempt,
+                    addr: self.targets.current().clone(),
+                })
+            }
+            ConnectionState::Connected(t) => {
+                let (local, peer) = self.connector.addrs(t).ok()?;
+                Some(Event::Connected { local, peer })
+            }
+        }
+    }
+
+    fn emit(&mut self, event: StreamEvent<C>) {
+        self.observers.emit(event);
+    }
+
+    // Return Pending until ConnectionState is diffrent than Connected
+    pub(crate) fn poll_into_transport(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<&mut C::Transport, Error>> {
+        loop {
+            if self.poll_give_up_timer(cx).is_ready() {
+                return Poll::Ready(Err(self.not_connected_error()));
+            }
+            match &mut self.state {
+                ConnectionState::Idle => self.connect(),
+                ConnectionState::Backoff(delay) => {
+                    ready!(delay.as_mut().poll(cx));
+                    debug!("RetryingStream => change state Backoff -> ConnectFuture");
+                    self.connect();
+                }
+                ConnectionState::ConnectFuture(cf) => {
+                    let span = self.telemetry.span();
+                    let _entered = span.enter();
+                    let res = ready!(cf.as_mut().poll(cx)).and_then(|transport| {
+                        self.connector.configure(&transport)?;
+                        Ok(transport)
+                    });
+                    let transport = match res {
+                        Ok(transport) => transport,
+                        Err(err) => {
+                            return Poll::Ready(Err(self.fail(ErrorCategory::Connect, err)))
+                        }
+                    };
+                    self.on_transport(transport)?;
+                }
+                ConnectionState::Handshake(hf) => {
+                    let span = self.telemetry.span();
+                    let _entered = span.enter();
+                    match ready!(hf.as_mut().poll(cx)) {
+                        Ok(transport) => self.set_connected(transport)?,
+                        Err(err) => {
+                            return Poll::Ready(Err(self.fail(ErrorCategory::Connect, err)))
+                        }
+                    }
+                }
+                ConnectionState::ShuttingDown(_)
+                | ConnectionState::Closed(_)
+                | ConnectionState::GaveUp(_) => {
+                    return Poll::Ready(Err(self.not_connected_error()));
+                }
+                ConnectionState::Connected(_) => {
+                    let transport = match self.poll_failback(cx) {
+                        Some(transport) => transport,
+                        None => break,
+                    };
+                    self.targets.select_primary();
+                    debug!("RetryingStream => fail back to {}", self.targets.current());
+                    self.replay_unacked();
+                    self.connected_at = None;
+                    if !self.observers.is_empty() {
+                        let error = Error::new(
+                            tokio::io::ErrorKind::ConnectionAborted,
+                            "fail back to primary target",
+                        );
+                        self.emit(Event::Disconnected {
+                            error: Arc::new(error),
+                        });
+                    }
+                    // connect to primary succeeded already, it is reported now
+                    self.telemetry
+                        .on_connect(self.targets.current(), self.attempt);
+                    self.emit(Event::Connecting {
+                        attempt: self.attempt,
+                        addr: self.targets.current().clone(),
+                    });
+                    self.on_transport(transport)?;
+                }
+            }
+        }
+
+        match self.state {
+            ConnectionState::Connected(ref mut t) => Poll::Ready(Ok(t)),
+            _ => unreachable!(),
+        }
+    }
+
+    // Run handshake on new transport or use it right away. Error is returned after reset.
+    fn on_transport(&mut self, transport: C::Transport) -> Result<(), Error> {
+        match &mut self.handshake {
+            Some(handshake) => {
+                self.state = ConnectionState::Handshake(handshake.handshake(transport));
+                debug!("RetryingStream => change state ConnectFuture -> Handshake");
+                Ok(())
+            }
+            None => self.set_connected(transport),
+        }
+    }
+
+    // While connected to backup target connect to primary once backup was used for
+    // failback_after. Return the new transport when that succeeded; otherwise primary is tried
+    // again after another failback_after.
+    fn poll_failback(&mut self, cx: &mut Context<'_>) -> Option<C::Transport> {
+        loop {
+            if self.failback_probe.is_none() {
+                let failback_at = self.targets.failback_at()?;
+                let timer = self
+                    .failback_timer
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(failback_at)));
+                if timer.as_mut().poll(cx).is_pending() {
+                    return None;
+                }
+                self.failback_timer = None;
+                debug!(
+                    "RetryingStream => try primary target {}",
+                    self.targets.primary()
+                );
+                let probe = self.connector.connect(self.targets.primary());
+                self.failback_probe = Some(Box::pin(probe));
+            }
+            let probe = self.failback_probe.as_mut()?;
+            let res = match probe.as_mut().poll(cx) {
+                Poll::Ready(res) => res,
+                Poll::Pending => return None,
+            };
+            self.failback_probe = None;
+            match res.and_then(|transport| {
+                self.connector.configure(&transport)?;
+                Ok(transport)
+            }) {
+                Ok(transport) => return Some(transport),
+                Err(err) => {
+                    debug!("RetryingStream => primary target still down: {}", err);
+                    self.targets.on_failback_failed();
+                }
+            }
+        }
+    }
+
+    // Give up when give_up_after passed while stream is reconnecting
+    fn poll_give_up_timer(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let reconnecting = matches!(
+            self.state,
+            ConnectionState::Backoff(_)
+                | ConnectionState::ConnectFuture(_)
+                | ConnectionState::Handshake(_)
+        );
+        let (after, deadline) = match (self.give_up_after, self.outage_since) {
+            (Some(after), Some(since)) if reconnecting => match since.checked_add(after) {
+                Some(deadline) => (after, deadline),
+                None => return Poll::Pending,
+            },
+            _ => return Poll::Pending,
+        };
+        let timer = self
+            .give_up_timer
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+        ready!(timer.as_mut().poll(cx));
+        let err = Error::new(
+            tokio::io::ErrorKind::TimedOut,
+            format!("no connection for {:?}", after),
+        );
+        self.give_up(&err);
+        Poll::Ready(())
+    }
+
+    // Go to Connected state. Error is returned after reset.
+    fn set_connected(&mut self, transport: C::Transport) -> Result<(), Error> {
+        let (local, peer) = match self.connector.addrs(&transport) {
+            Ok(addrs) => addrs,
+            Err(err) => return Err(self.fail(ErrorCategory::Connect, err)),
+        };
+        self.state = ConnectionState::Connected(transport);
+        self.connected_at = Some(Instant::now());
+        self.failed_connects = 0;
+        self.outage_since = None;
+        self.give_up_timer = None;
+        self.telemetry.on_connected(&local, &peer);
+        self.targets.on_connected();
+        debug!("RetryingStream => change state to Connected");
+        self.emit(Event::Connected { local, peer });
+        Ok(())
+    }
+
+    // Go to Closed state. Pending connect or handshake is dropped.
+    fn close(&mut self, transport: Option<C::Transport>) {
+        self.state = ConnectionState::Closed(transport);
+        self.connected_at = None;
+        self.failback_timer = None;
+        self.failback_probe = None;
+        self.give_up_timer = None;
+        if let Some(queue) = &mut self.queue {
+            queue.on_closed();
+        }
+        self.telemetry.on_closed();
+        debug!("RetryingStream => change state to Closed");
+        self.emit(Event::Closed);
+    }
+
+    fn connect(&mut self) {
+        let cf = self.connector.connect(self.targets.current());
+        self.state = ConnectionState::ConnectFuture(Box::pin(cf));
+        self.failback_timer = None;
+        self.failback_probe = None;
+        self.telemetry
+            .on_connect(self.targets.current(), self.attempt);
+        self.emit(Event::Connecting {
+            attempt: self.attempt,
+            addr: self.targets.current().clone(),
+        });
+    }
+
+    fn reset(&mut self, err: &Error) {
+        debug!("RetryingStream => reset was called!");
+        if self.connected_at.is_some() {
+            self.replay_unacked();
+        }
+        let connected_at = self.connected_at.take();
+        self.telemetry.on_failure(err, connected_at.is_some());
+        if !self.observers.is_empty() {
+            let error = Arc::new(clone_error(err));
+            match connected_at {
+                Some(_) => self.emit(Event::Disconnected { error }),
+                None => self.emit(Event::ConnectFailed {
+                    attempt: self.attempt,
+                    error,
+                }),
+            }
+        }
+        if let Some(connected_at) = connected_at {
+            if connected_at.elapsed() >= self.stable_period {
+                self.attempt = 0;
+                self.policy.reset();
+            }
+        }
+        if connected_at.is_none() {
+            self.failed_connects = self.failed_connects.saturating_add(1);
+        }
+        let outage_since = *self.outage_since.get_or_insert_with(Instant::now);
+        let gave_up = self
+            .max_attempts
+            .is_some_and(|max| self.failed_connects >= max)
+            || self
+                .give_up_after
+                .is_some_and(|after| outage_since.elapsed() >= after);
+        if gave_up {
+            self.give_up(err);
+            return;
+        }
+        self.targets.advance(connected_at.is_some());
+        self.attempt = self.attempt.saturating_add(1);
+
+        let delay = self.policy.next_delay(self.attempt);
+        if delay == Duration::from_secs(0) {
+            self.connect();
+        } else {
+            debug!("RetryingStream => backoff for {:?}", delay);
+            self.state = ConnectionState::Backoff(Box::pin(tokio::time::sleep(delay)));
+            self.telemetry.on_backoff(delay);
+            self.emit(Event::BackingOff { delay });
+        }
+    }
+
+    // Go to GaveUp state with `err` as the last error. Pending connect or handshake is dropped.
+    fn give_up(&mut self, err: &Error) {
+        debug!(
+            "RetryingStream => gave up after {} attempts",
+            self.failed_connects
+        );
+        let error = Arc::new(clone_error(err));
+        self.state = ConnectionState::GaveUp(RetryingError::new(
+            ErrorCategory::GaveUp,
+            self.failed_connects,
+            self.targets.current().to_string(),
+            Some(error.clone()),
+        ));
+        self.failback_timer = None;
+        self.failback_probe = None;
+        self.give_up_timer = None;
+        self.telemetry.on_gave_up(self.failed_connects);
+        self.emit(Event::GaveUp {
+            attempts: self.failed_connects,
+            error,
+        });
+    }
+
+    // Mark bytes current connection didn't deliver for writing to the next one
+    fn replay_unacked(&mut self) {
+        let unacked = match &self.state {
+            ConnectionState::Connected(t) => self.connector.unacked_bytes(t),
+            _ => None,
+        };
+        debug!("RetryingStream => unacknowledged bytes: {:?}", unacked);
+        self.lost_unacked = (self.telemetry.stats.connects, unacked);
+        if let Some(replay) = &mut self.replay {
+            replay.on_disconnect(unacked);
+        }
+        self.replay_timer = None;
+    }
+
+    // Bytes written to current connection and not acknowledged by peer
+    pub(crate) fn unacked_bytes(&self) -> Option<usize> {
+        match &self.state {
+            ConnectionState::Connected(t) => self.connector.unacked_bytes(t),
+            _ => None,
+        }
+    }
+
+    // Bytes peer didn't acknowledge when connection number `connection` was lost, see
+    // `connects()`
+    pub(crate) fn lost_unacked(&self, connection: u64) -> Option<usize> {
+        match self.lost_unacked {
+            (lost, unacked) if lost == connection => unacked,
+            _ => None,
+        }
+    }
+
+    // Like poll_into_transport, but also starts writing bytes left from previous connection and
+    // queued ones. Peer may wait for them before it sends anything, so they are written even
+    // when caller only reads; it doesn't wait until they are.
+    pub(crate) fn poll_connected(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<&mut C::Transport, Error>> {
+        ready!(self.poll_into_transport(cx))?;
+        if let Poll::Ready(Err(err)) = self.poll_resend(cx) {
+            return Poll::Ready(Err(err));
+        }
+        match self.state {
+            ConnectionState::Connected(ref mut t) => Poll::Ready(Ok(t)),
+            _ => unreachable!(),
+        }
+    }
+
+    // Write replayed and then queued bytes, before any new data
+    fn poll_resend(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if let Err(err) = ready!(self.poll_replay(cx)) {
+            return Poll::Ready(self.call_reset_if_io_is_closed2(Err(err)));
+        }
+        self.poll_drain_queue(cx)
+    }
+
+    // Write bytes left from previous connection before any new data
+    fn poll_replay(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        loop {
+            let (replay, t) = match (&mut self.replay, &mut self.state) {
+                (Some(replay), ConnectionState::Connected(t)) if !replay.pending().is_empty() => {
+                    (replay, t)
+                }
+                _ => return Poll::Ready(Ok(())),
+            };
+            match ready!(Pin::new(t).poll_write(cx, replay.pending())) {
+                Ok(0) => {
+                    return Poll::Ready(Err(Error::new(
+                        tokio::io::ErrorKind::WriteZero,
+                        "failed to write replayed bytes",
+                    )))
+                }
+                Ok(n) => {
+                    replay.advance(n);
+                    self.telemetry.on_write(n);
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    // Number of bytes from `len` that can be written without overflowing replay buffer
+    fn poll_replay_room(&mut self, cx: &mut Context<'_>, len: usize) -> Poll<Result<usize, Error>> {
+        loop {
+            let (replay, t) = match (&mut self.replay, &mut self.state) {
+                (Some(replay), ConnectionState::Connected(t))
+                    if replay.policy().overflow == Overflow::Block && len > 0 =>
+                {
+                    (replay, t)
+                }
+                _ => return Poll::Ready(Ok(len)),
+            };
+            if replay.free() == 0 {
+                match self.connector.unacked_bytes(t) {
+                    Some(unacked) => replay.acknowledge(unacked),
+                    // there is no way to wait for acknowledgement, drop oldest bytes
+                    None => return Poll::Ready(Ok(len)),
+                }
+            }
+            if replay.free() > 0 {
+                self.replay_timer = None;
+                return Poll::Ready(Ok(len.min(replay.free())));
+            }
+            // count of unacknowledged bytes doesn't change after connection broke, empty write
+            // returns the error
+            if let Poll::Ready(Err(err)) = Pin::new(t).poll_write(cx, &[]) {
+                return Poll::Ready(Err(err));
+            }
+            // acknowledgements don't wake the task, check again later
+            let timer = self
+                .replay_timer
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(REPLAY_POLL_INTERVAL)));
+            ready!(timer.as_mut().poll(cx));
+            self.replay_timer = None;
+        }
+    }
+
+    // Like poll_into_transport, but errors after which stream reconnects are not returned, for
+    // callers that wait for connection
+    pub(crate) fn poll_reconnect(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match ready!(self.poll_into_transport(cx)) {
+            Ok(_) => Poll::Ready(Ok(())),
+            Err(err) if is_reconnecting(&err) => yield_now(cx),
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+
+    // With write queue failed connect doesn't fail writes, caller can queue data while stream
+    // reconnects
+    fn poll_queue_transport(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if self.queue.is_some() {
+            return self.poll_reconnect(cx);
+        }
+        ready!(self.poll_into_transport(cx))?;
+        Poll::Ready(Ok(()))
+    }
+
+    // Queue bytes written while not connected. Pending without queue or when it is full; task
+    // is woken by connect.
+    fn poll_enqueue(&mut self, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        let queue = match &mut self.queue {
+            Some(queue) => queue,
+            None => return Poll::Pending,
+        };
+        match queue.push(buf)? {
+            Some(n) => Poll::Ready(Ok(n)),
+            None if queue.policy().overflow == QueueOverflow::Error => {
+                Poll::Ready(Err(self.error(ErrorCategory::QueueFull, None).into()))
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    // Write bytes queued while connecting. Errors of spool file are returned without reset.
+    fn poll_drain_queue(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        loop {
+            let len = match &mut self.queue {
+                Some(queue) if queue.len() > 0 => queue.front()?.len(),
+                _ => return Poll::Ready(Ok(())),
+            };
+            let len = match ready!(self.poll_replay_room(cx, len)) {
+                Ok(len) => len,
+                Err(err) => return Poll::Ready(self.call_reset_if_io_is_closed2(Err(err))),
+            };
+            let (queue, t) = match (&mut self.queue, &mut self.state) {
+                (Some(queue), ConnectionState::Connected(t)) => (queue, t),
+                _ => return Poll::Ready(Ok(())),
+            };
+            let queued = &queue.front()?[..len];
+            let n = match ready!(Pin::new(t).poll_write(cx, queued)) {
+                Ok(0) => Err(Error::new(
+                    tokio::io::ErrorKind::WriteZero,
+                    "failed to write queued bytes",
+                )),
+                res => res,
+            };
+            let n = match n {
+                Ok(n) => n,
+                Err(err) => return Poll::Ready(self.call_reset_if_io_is_closed2(Err(err))),
+            };
+            if let Some(replay) = &mut self.replay {
+                replay.record(&queued[..n]);
+            }
+            self.telemetry.on_write(n);
+            queue.advance(n)?;
+        }
+    }
+
+    // Peer closed the connection, reset with error reported in Disconnected event
+    fn reset_on_eof(&mut self) -> Error {
+        debug!("RetryingStream => peer closed connection");
+        let err = Error::new(
+            tokio::io::ErrorKind::ConnectionReset,
+            "connection closed by peer",
+        );
+        self.fail(ErrorCategory::Disconnected, err)
+    }
+
+    // Write to current transport without reconnecting, for layers that frame data per connection
+    pub(crate) fn poll_write_connected(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let t = match &mut self.state {
+            ConnectionState::Connected(t) => t,
+            _ => return Poll::Ready(Err(self.not_connected_error())),
+        };
+        let res = match ready!(Pin::new(t).poll_write(cx, buf)) {
+            Ok(0) if !buf.is_empty() => Err(Error::new(
+                tokio::io::ErrorKind::WriteZero,
+                "connection closed by peer",
+            )),
+            res => res,
+        };
+        if let Ok(n) = res {
+            self.telemetry.on_write(n);
+        }
+        Poll::Ready(self.call_reset_if_io_is_closed2(res))
+    }
+
+    // Reset or close according to error classifier. Surfaced error is returned as is.
+    pub(crate) fn call_reset_if_io_is_closed2<T>(
+        &mut self,
+        res: Result<T, Error>,
+    ) -> Result<T, Error> {
+        let err = match res {
+            Ok(ok) => return Ok(ok),
+            Err(err) => err,
+        };
+        match self.classifier.classify(&err) {
+            ErrorAction::Retry => Err(self.fail(ErrorCategory::Disconnected, err)),
+            ErrorAction::Surface => Err(err),
+            ErrorAction::Fatal => {
+                debug!("RetryingStream => fatal error: {}", err);
+                self.connected_at = None;
+                self.telemetry.on_failure(&err, true);
+                if !self.observers.is_empty() {
+                    self.emit(Event::Disconnected {
+                        error: Arc::new(clone_error(&err)),
+                    });
+                }
+                let err = self.error(ErrorCategory::Fatal, Some(err));
+                self.close(None);
+                Err(err.into())
+            }
+        }
+    }
+}
+
+// Pending after error of reconnecting stream. Next attempt may fail right away again, e.g. when
+// bind address is not available, so let other tasks run before it.
+pub(crate) fn yield_now<T>(cx: &mut Context<'_>) -> Poll<T> {
+    cx.waker().wake_by_ref();
+    Poll::Pending
+}
+
+impl<C: Connector> AsyncRead for RetryingStream<C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        // after shutdown rest of data can still be read, but nothing is reconnected
+        if let ConnectionState::ShuttingDown(t) | ConnectionState::Closed(Some(t)) = &mut this.state
+        {
+            let filled = buf.filled().len();
+            let res = ready!(Pin::new(t).poll_read(cx, buf));
+            this.telemetry.on_read(buf.filled().len() - filled);
+            return Poll::Ready(res);
+        }
+        loop {
+            let t = ready!(this.poll_connected(cx))?;
+            let filled = buf.filled().len();
+            let res = ready!(Pin::new(t).poll_read(cx, buf));
+            this.telemetry.on_read(buf.filled().len() - filled);
+            if res.is_ok() && buf.filled().len() == filled && buf.remaining() > 0 {
+                match this.eof_policy {
+                    EofPolicy::Return => (),
+                    EofPolicy::Reconnect => {
+                        this.reset_on_eof();
+                        continue;
+                    }
+                    EofPolicy::Error => return Poll::Ready(Err(this.reset_on_eof())),
+                }
+            }
+            return Poll::Ready(this.call_reset_if_io_is_closed2(res));
+        }
+    }
+}
+
+impl<C: Connector> AsyncWrite for RetryingStream<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        loop {
+            if this.poll_queue_transport(cx)?.is_pending() {
+                return this.poll_enqueue(buf);
+            }
+            ready!(this.poll_resend(cx))?;
+            let len = match ready!(this.poll_replay_room(cx, buf.len())) {
+                Ok(len) => len,
+                Err(err) => return Poll::Ready(this.call_reset_if_io_is_closed2(Err(err))),
+            };
+            let t = match this.state {
+                ConnectionState::Connected(ref mut t) => t,
+                _ => unreachable!(),
+            };
+            let res = ready!(Pin::new(t).poll_write(cx, &buf[..len]));
+            if let Ok(n) = res {
+                this.telemetry.on_write(n);
+                if let Some(replay) = &mut this.replay {
+                    replay.record(&buf[..n]);
+                }
+            }
+            if matches!(res, Ok(0)) && !buf.is_empty() {
+                match this.eof_policy {
+                    EofPolicy::Return => (),
+                    EofPolicy::Reconnect => {
+                        this.reset_on_eof();
+                        continue;
+                    }
+                    EofPolicy::Error => return Poll::Ready(Err(this.reset_on_eof())),
+                }
+            }
+            return Poll::Ready(this.call_reset_if_io_is_closed2(res));
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_queue_transport(cx))?;
+        ready!(this.poll_resend(cx))?;
+        let t = match this.state {
+            ConnectionState::Connected(ref mut t) => t,
+            _ => unreachable!(),
+        };
+        let res = ready!(Pin::new(t).poll_flush(cx));
+        Poll::Ready(this.call_reset_if_io_is_closed2(res))
+    }
+
+    /// Shut down connected transport and go to closed state.
+    ///
+    /// When not connected, pending connect is cancelled. Closed stream never reconnects: writes
+    /// return `BrokenPipe` and reads return data left in shut down transport.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.state {
+                ConnectionState::Connected(_) => {
+                    // queued bytes go before shutdown; when they can't, stream is closed anyway
+                    // and only spooled ones are kept
+                    if let Err(err) = ready!(this.poll_drain_queue(cx)) {
+                        if !this.is_closed() {
+                            this.close(None);
+                        }
+                        return Poll::Ready(Err(err));
+                    }
+                    let state = std::mem::replace(&mut this.state, ConnectionState::Closed(None));
+                    if let ConnectionState::Connected(t) = state {
+                        this.state = ConnectionState::ShuttingDown(t);
+                    }
+                }
+                ConnectionState::ShuttingDown(t) => {
+                    let res = ready!(Pin::new(t).poll_shutdown(cx));
+                    let state = std::mem::replace(&mut this.state, ConnectionState::Closed(None));
+                    match (state, &res) {
+                        (ConnectionState::ShuttingDown(t), Ok(())) => this.close(Some(t)),
+                        _ => this.close(None),
+                    }
+                    return Poll::Ready(res);
+                }
+                ConnectionState::Closed(_) => return Poll::Ready(Ok(())),
+                ConnectionState::GaveUp(_)
+                | ConnectionState::Idle
+                | ConnectionState::Backoff(_)
+                | ConnectionState::ConnectFuture(_)
+                | ConnectionState::Handshake(_) => {
+                    this.close(None);
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::mock::{Fault, MockConnector, MockTransport};
+    use futures::task::ArcWake;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, ErrorKind};
+
+    // Poll `future` once without waiting
+    pub(crate) fn poll_once<F: Future>(future: F) -> Poll<F::Output> {
+        let waker = futures::task::noop_waker();
+        std::pin::pin!(future).poll(&mut Context::from_waker(&waker))
+    }
+
+    pub(crate) fn category(err: &Error) -> Option<ErrorCategory> {
+        RetryingError::from_io(err).map(RetryingError::category)
+    }
+
+    #[tokio::test]
+    async fn connects_on_first_poll() {
+        let connector = MockConnector::default();
+        let mut peer = connector.accept("a");
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        assert!(!stream.is_connected());
+        assert!(connector.attempts().is_empty());
+
+        stream.write_all(b"ping").await.unwrap();
+        assert!(stream.is_connected());
+        let mut buf = [0; 4];
+        peer.io.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        peer.io.write_all(b"pong").await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+        assert_eq!(connector.attempts(), ["a"]);
+    }
+
+    #[tokio::test]
+    async fn error_resets_and_next_poll_reconnects() {
+        let connector = MockConnector::default();
+        let first = connector.accept("a");
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        stream.write_all(b"x").await.unwrap();
+
+        first.inject(Fault::Error(ErrorKind::ConnectionReset));
+        let mut buf = [0; 4];
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+        assert_eq!(category(&err), Some(ErrorCategory::Disconnected));
+        assert!(!stream.is_connected());
+
+        let mut second = connector.accept("a");
+        second.io.write_all(b"new").await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 3);
+        assert_eq!(&buf[..3], b"new");
+        assert_eq!(connector.attempts(), ["a", "a"]);
+    }
+
+    #[tokio::test]
+    async fn failed_connect_is_returned_and_retried() {
+        let connector = MockConnector::default();
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        let err = stream.write(b"x").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(category(&err), Some(ErrorCategory::Connect));
+
+        let mut peer = connector.accept("a");
+        stream.write_all(b"x").await.unwrap();
+        let mut buf = [0; 1];
+        peer.io.read_exact(&mut buf).await.unwrap();
+        assert_eq!(connector.attempts(), ["a", "a"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_is_pending() {
+        let connector = MockConnector::default();
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        stream.set_reconnect_policy(ConstantBackoff::new(Duration::from_secs(1)));
+        assert!(stream.write(b"x").await.is_err());
+
+        let _peer = connector.accept("a");
+        assert!(poll_once(stream.write(b"x")).is_pending());
+        assert!(poll_once(stream.flush()).is_pending());
+        assert_eq!(connector.attempts(), ["a"]);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(stream.write(b"x").await.unwrap(), 1);
+        assert_eq!(connector.attempts(), ["a", "a"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_back_only_when_primary_is_up() {
+        let connector = MockConnector::default();
+        let targets = TargetSet::new(vec!["primary", "backup"], Strategy::Failover).unwrap();
+        let mut stream = RetryingStream::with_target_set(connector.clone(), targets);
+        stream.set_failback_after(Some(Duration::from_secs(10)));
+        let mut backup = connector.accept("backup");
+        assert!(stream.write(b"x").await.is_err());
+        stream.write_all(b"a").await.unwrap();
+        assert_eq!(stream.target(), &"backup");
+
+        // primary is still down, backup connection is kept
+        tokio::time::advance(Duration::from_secs(10)).await;
+        stream.write_all(b"b").await.unwrap();
+        assert_eq!(stream.target(), &"backup");
+        assert_eq!(connector.attempts(), ["primary", "backup", "primary"]);
+        let mut buf = [0; 2];
+        backup.io.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ab");
+
+        // and primary is tried again after another period
+        let mut primary = connector.accept("primary");
+        tokio::time::advance(Duration::from_secs(5)).await;
+        stream.write_all(b"c").await.unwrap();
+        assert_eq!(stream.target(), &"backup");
+        tokio::time::advance(Duration::from_secs(5)).await;
+        stream.write_all(b"d").await.unwrap();
+        assert_eq!(stream.target(), &"primary");
+        let mut buf = [0; 1];
+        primary.io.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"d");
+        // backup connection is closed after switch
+        backup.io.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"c");
+        assert_eq!(backup.io.read(&mut buf).await.unwrap(), 0);
+    }
+
+    fn describe(event: &StreamEvent<MockConnector>) -> String {
+        match event {
+            Event::Connecting { attempt, addr } => format!("Connecting {} {}", attempt, addr),
+            Event::Connected { peer, .. } => format!("Connected {}", peer),
+            Event::ConnectFailed { attempt, error } => {
+                format!("ConnectFailed {} {:?}", attempt, error.kind())
+            }
+            Event::Disconnected { error } => format!("Disconnected {:?}", error.kind()),
+            Event::BackingOff { delay } => format!("BackingOff {:?}", delay),
+            Event::Closed => "Closed".to_owned(),
+            Event::GaveUp { attempts, error } => format!("GaveUp {} {:?}", attempts, error.kind()),
+        }
+    }
+
+    // Events passed to callback, described
+    fn record(stream: &mut RetryingStream<MockConnector>) -> Arc<std::sync::Mutex<Vec<String>>> {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        stream.on_event(move |event| recorded.lock().unwrap().push(describe(event)));
+        events
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn events_follow_state_transitions() {
+        let connector = MockConnector::default();
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        stream.set_reconnect_policy(ConstantBackoff::new(Duration::from_secs(1)));
+        // idle stream has no current state to report
+        let events = record(&mut stream);
+        assert!(events.lock().unwrap().is_empty());
+
+        assert!(stream.write(b"x").await.is_err());
+        let peer = connector.accept("a");
+        stream.write_all(b"x").await.unwrap();
+        peer.inject(Fault::Error(ErrorKind::ConnectionReset));
+        assert!(stream.read(&mut [0; 1]).await.is_err());
+        stream.shutdown().await.unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "Connecting 0 a",
+                "ConnectFailed 0 ConnectionRefused",
+                "BackingOff 1s",
+                "Connecting 1 a",
+                "Connected a",
+                "Disconnected ConnectionReset",
+                "BackingOff 1s",
+                "Closed",
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn observers_get_current_state_first() {
+        let connector = MockConnector::default();
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        stream.set_reconnect_policy(ConstantBackoff::new(Duration::from_secs(1)));
+        assert!(stream.write(b"x").await.is_err());
+        let backing_off = record(&mut stream);
+        assert_eq!(*backing_off.lock().unwrap(), ["BackingOff 1s"]);
+
+        let _peer = connector.accept("a");
+        stream.write_all(b"x").await.unwrap();
+        let connected = record(&mut stream);
+        let mut events = stream.subscribe();
+        assert_eq!(describe(&events.try_recv().unwrap()), "Connected a");
+        assert!(events.try_recv().is_err());
+
+        stream.shutdown().await.unwrap();
+        assert_eq!(describe(&events.try_recv().unwrap()), "Closed");
+        assert_eq!(*connected.lock().unwrap(), ["Connected a", "Closed"]);
+        assert_eq!(
+            *backing_off.lock().unwrap(),
+            ["BackingOff 1s", "Connecting 1 a", "Connected a", "Closed"]
+        );
+        // late subscriber of closed stream
+        let mut events = stream.subscribe();
+        assert_eq!(describe(&events.try_recv().unwrap()), "Closed");
+    }
+
+    #[tokio::test]
+    async fn failed_handshake_resets() {
+        let connector = MockConnector::default();
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        stream.set_handshake(|mut transport: MockTransport| async move {
+            transport.write_all(b"login").await?;
+            let mut reply = [0; 2];
+            transport.read_exact(&mut reply).await?;
+            match &reply {
+                b"ok" => Ok(transport),
+                _ => Err(Error::new(ErrorKind::PermissionDenied, "login refused")),
+            }
+        });
+        let events = record(&mut stream);
+
+        let mut refused = connector.accept("a");
+        refused.io.write_all(b"no").await.unwrap();
+        let err = stream.write(b"data").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(category(&err), Some(ErrorCategory::Connect));
+        assert!(!stream.is_connected());
+        // transport of failed handshake is dropped
+        let mut buf = [0; 5];
+        refused.io.read_exact(&mut buf).await.unwrap();
+        assert_eq!(refused.io.read(&mut buf).await.unwrap(), 0);
+
+        let mut accepted = connector.accept("a");
+        accepted.io.write_all(b"ok").await.unwrap();
+        stream.write_all(b"data").await.unwrap();
+        let mut buf = [0; 9];
+        accepted.io.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"logindata");
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "Connecting 0 a",
+                "ConnectFailed 0 PermissionDenied",
+                "Connecting 1 a",
+                "Connected a",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn closed_stream_reads_rest_and_rejects_writes() {
+        let connector = MockConnector::default();
+        let mut peer = connector.accept("a");
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        stream.write_all(b"x").await.unwrap();
+        peer.io.write_all(b"bye").await.unwrap();
+
+        stream.shutdown().await.unwrap();
+        assert!(stream.is_closed());
+        let mut buf = [0; 4];
+        assert_eq!(peer.io.read(&mut buf).await.unwrap(), 1);
+        assert_eq!(peer.io.read(&mut buf).await.unwrap(), 0);
+
+        let err = stream.write(b"y").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        assert_eq!(category(&err), Some(ErrorCategory::Closed));
+        assert!(stream.flush().await.is_err());
+        stream.read_exact(&mut buf[..3]).await.unwrap();
+        assert_eq!(&buf[..3], b"bye");
+        drop(peer);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        // shutting down again is fine, nothing reconnects
+        stream.shutdown().await.unwrap();
+        assert_eq!(connector.attempts(), ["a"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_cancels_reconnect() {
+        let connector = MockConnector::default();
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        stream.set_reconnect_policy(ConstantBackoff::new(Duration::from_secs(1)));
+        assert!(stream.write(b"x").await.is_err());
+        let _peer = connector.accept("a");
+        stream.shutdown().await.unwrap();
+        assert!(stream.is_closed());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let err = stream.read(&mut [0; 1]).await.unwrap_err();
+        assert_eq!(category(&err), Some(ErrorCategory::Closed));
+        let err = stream.write(b"x").await.unwrap_err();
+        assert_eq!(category(&err), Some(ErrorCategory::Closed));
+        assert_eq!(connector.attempts(), ["a"]);
+    }
+
+    #[tokio::test]
+    async fn surfaced_error_keeps_connection() {
+        let connector = MockConnector::default();
+        let mut peer = connector.accept("a");
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        stream.write_all(b"x").await.unwrap();
+
+        peer.inject(Fault::Error(ErrorKind::InvalidData));
+        let err = stream.read(&mut [0; 1]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(category(&err), None);
+        assert!(stream.is_connected());
+        peer.io.write_all(b"y").await.unwrap();
+        let mut buf = [0; 1];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"y");
+        assert_eq!(connector.attempts(), ["a"]);
+    }
+
+    #[tokio::test]
+    async fn fatal_error_closes() {
+        let connector = MockConnector::default();
+        let peer = connector.accept("a");
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        stream.set_error_classifier(|err: &Error| match err.kind() {
+            ErrorKind::PermissionDenied => ErrorAction::Fatal,
+            _ => ErrorAction::Retry,
+        });
+        stream.write_all(b"x").await.unwrap();
+        let events = record(&mut stream);
+
+        peer.inject(Fault::Error(ErrorKind::PermissionDenied));
+        let err = stream.write(b"y").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(category(&err), Some(ErrorCategory::Fatal));
+        assert!(stream.is_closed());
+        let err = stream.read(&mut [0; 1]).await.unwrap_err();
+        assert_eq!(category(&err), Some(ErrorCategory::Closed));
+        assert_eq!(connector.attempts(), ["a"]);
+        assert_eq!(
+            *events.lock().unwrap(),
+            ["Connected a", "Disconnected PermissionDenied", "Closed"]
+        );
+    }
+
+    #[tokio::test]
+    async fn write_eof_follows_policy() {
+        for policy in [EofPolicy::Return, EofPolicy::Reconnect, EofPolicy::Error] {
+            let connector = MockConnector::default();
+            let first = connector.accept("a");
+            let mut second = connector.accept("a");
+            let mut stream = RetryingStream::new(connector.clone(), "a");
+            stream.set_eof_policy(policy);
+            stream.write_all(b"x").await.unwrap();
+
+            first.inject(Fault::Eof);
+            let res = stream.write(b"y").await;
+            match policy {
+                EofPolicy::Return => {
+                    assert_eq!(res.unwrap(), 0);
+                    assert_eq!(connector.attempts(), ["a"]);
+                    continue;
+                }
+                // written to new connection
+                EofPolicy::Reconnect => assert_eq!(res.unwrap(), 1),
+                EofPolicy::Error => {
+                    let err = res.unwrap_err();
+                    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+                    assert_eq!(category(&err), Some(ErrorCategory::Disconnected));
+                    stream.write_all(b"y").await.unwrap();
+                }
+            }
+            assert_eq!(connector.attempts(), ["a", "a"], "{:?}", policy);
+            let mut buf = [0; 1];
+            second.io.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"y");
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts_until_revived() {
+        let connector = MockConnector::default();
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        stream.set_max_attempts(Some(3));
+        for _ in 0..2 {
+            let err = stream.write(b"x").await.unwrap_err();
+            assert_eq!(category(&err), Some(ErrorCategory::Connect));
+        }
+        let err = stream.write(b"x").await.unwrap_err();
+        assert_eq!(category(&err), Some(ErrorCategory::Connect));
+        assert!(stream.has_given_up());
+
+        // no more attempts until revived, last error is kept
+        let err = stream.read(&mut [0; 1]).await.unwrap_err();
+        let gave_up = RetryingError::from_io(&err).unwrap();
+        assert_eq!(gave_up.category(), ErrorCategory::GaveUp);
+        assert_eq!(gave_up.attempt(), 3);
+        assert_eq!(
+            gave_up.io_error().unwrap().kind(),
+            ErrorKind::ConnectionRefused
+        );
+        assert_eq!(connector.attempts().len(), 3);
+
+        // limit counts from zero again
+        stream.revive();
+        assert!(!stream.has_given_up());
+        for _ in 0..2 {
+            assert!(stream.write(b"x").await.is_err());
+        }
+        assert!(!stream.has_given_up());
+        let _peer = connector.accept("a");
+        stream.write_all(b"x").await.unwrap();
+        assert_eq!(connector.attempts().len(), 6);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_time_during_backoff() {
+        let connector = MockConnector::default();
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        stream.set_reconnect_policy(ConstantBackoff::new(Duration::from_secs(3)));
+        stream.set_give_up_after(Some(Duration::from_millis(100)));
+        let events = record(&mut stream);
+        let start = Instant::now();
+        assert!(stream.write(b"x").await.is_err());
+        assert!(!stream.has_given_up());
+
+        // backoff is longer than the limit, stream gives up without waiting for it
+        let err = stream.write(b"x").await.unwrap_err();
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+        assert!(stream.has_given_up());
+        let gave_up = RetryingError::from_io(&err).unwrap();
+        assert_eq!(gave_up.category(), ErrorCategory::GaveUp);
+        assert_eq!(gave_up.io_error().unwrap().kind(), ErrorKind::TimedOut);
+        assert_eq!(connector.attempts(), ["a"]);
+        assert_eq!(events.lock().unwrap().last().unwrap(), "GaveUp 1 TimedOut");
+
+        let _peer = connector.accept("a");
+        stream.revive();
+        stream.write_all(b"x").await.unwrap();
+        assert!(stream.is_connected());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stats_count_connects_failures_and_bytes() {
+        let connector = MockConnector::default();
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        assert_eq!(stream.stats(), Stats::default());
+
+        // refused, then connected
+        assert!(stream.write(b"x").await.is_err());
+        let mut first = connector.accept("a");
+        stream.write_all(b"abc").await.unwrap();
+        first.io.write_all(b"12").await.unwrap();
+        stream.read_exact(&mut [0; 2]).await.unwrap();
+        tokio::time::advance(Duration::from_secs(5)).await;
+        let stats = stream.stats();
+        assert_eq!(stats.connect_attempts, 2);
+        assert_eq!((stats.connects, stats.connect_failures), (1, 1));
+        assert_eq!((stats.bytes_written, stats.bytes_read), (3, 2));
+        assert_eq!(stats.connection_age, Some(Duration::from_secs(5)));
+
+        // connection breaks, next one has its own byte counters
+        let _second = connector.accept("a");
+        first.inject(Fault::Error(ErrorKind::BrokenPipe));
+        assert!(stream.write(b"x").await.is_err());
+        assert_eq!(stream.stats().connection_age, None);
+        stream.write_all(b"de").await.unwrap();
+        let stats = stream.stats();
+        assert_eq!(stats.connect_attempts, 3);
+        assert_eq!((stats.connects, stats.disconnects), (2, 1));
+        assert_eq!(stats.bytes_written, 5);
+        assert_eq!(stats.connection_bytes_written, 2);
+        assert_eq!(stats.connection_bytes_read, 0);
+        assert_eq!(stats.last_disconnect_kind, Some(ErrorKind::BrokenPipe));
+        assert_eq!(
+            stats.failures_by_kind,
+            [
+                (ErrorKind::ConnectionRefused, 1),
+                (ErrorKind::BrokenPipe, 1)
+            ]
+            .into()
+        );
+    }
+
+    #[tokio::test]
+    async fn lost_bytes_are_replayed_when_only_reading() {
+        let connector = MockConnector::default();
+        let first = connector.accept("a");
+        let mut second = connector.accept("a");
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        stream.set_replay_policy(Some(ReplayPolicy::new(1024, Overflow::DropOldest)));
+        stream.write_all(b"abcdef").await.unwrap();
+
+        // peer got "abc" before it reset the connection
+        first.set_unacked(Some(3));
+        first.inject(Fault::Error(ErrorKind::ConnectionReset));
+        let err = stream.read(&mut [0; 1]).await.unwrap_err();
+        assert_eq!(category(&err), Some(ErrorCategory::Disconnected));
+
+        // new peer answers only after it got the rest
+        let peer = async {
+            let mut buf = [0; 3];
+            second.io.read_exact(&mut buf).await.unwrap();
+            second.io.write_all(b"ok").await.unwrap();
+            buf
+        };
+        let mut answer = [0; 2];
+        let read = async { tokio::join!(stream.read_exact(&mut answer), peer) };
+        let (res, resent) = tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .unwrap();
+        res.unwrap();
+        assert_eq!(&resent, b"def");
+        assert_eq!(&answer, b"ok");
+    }
+
+    #[test]
+    fn failing_reconnect_yields() {
+        struct Wakes(AtomicUsize);
+
+        impl ArcWake for Wakes {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let connector = MockConnector::default();
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        let wakes = Arc::new(Wakes(AtomicUsize::new(0)));
+        let waker = futures::task::waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+        // every refused attempt returns Pending and wakes the task, instead of trying again in
+        // a loop; next attempt is started by reset and polled on next call
+        for attempt in 1..=3 {
+            assert!(stream.poll_reconnect(&mut cx).is_pending());
+            assert_eq!(connector.attempts().len(), attempt + 1);
+            assert_eq!(wakes.0.load(Ordering::Relaxed), attempt);
+        }
+
+        let _peer = connector.accept("a");
+        assert!(matches!(
+            stream.poll_reconnect(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+    }
+
+    #[tokio::test]
+    async fn queued_bytes_are_written_when_only_reading() {
+        let connector = MockConnector::default();
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        stream.set_write_queue(Some(QueuePolicy::new(1024, QueueOverflow::Block)));
+        // connect is refused, bytes wait in queue
+        stream.write_all(b"ping").await.unwrap();
+        assert_eq!(stream.queued_bytes(), 4);
+
+        let mut peer = connector.accept("a");
+        let answer = async {
+            let mut buf = [0; 4];
+            peer.io.read_exact(&mut buf).await.unwrap();
+            peer.io.write_all(b"pong").await.unwrap();
+            buf
+        };
+        let mut buf = [0; 4];
+        let read = async { tokio::join!(stream.read_exact(&mut buf), answer) };
+        let (res, received) = tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .unwrap();
+        res.unwrap();
+        assert_eq!(&received, b"ping");
+        assert_eq!(&buf, b"pong");
+        assert_eq!(stream.queued_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_drain_on_shutdown_closes() {
+        let connector = MockConnector::default();
+        let mut stream = RetryingStream::new(connector.clone(), "a");
+        stream.set_write_queue(Some(QueuePolicy::new(128 * 1024, QueueOverflow::Block)));
+        stream.write_all(&[1; 100 * 1024]).await.unwrap();
+
+        // peer buffer takes only part of the queue
+        let peer = connector.accept("a");
+        assert!(poll_once(stream.flush()).is_pending());
+        assert!(stream.is_connected());
+        assert!(stream.queued_bytes() > 0);
+
+        peer.inject(Fault::Error(ErrorKind::BrokenPipe));
+        let err = stream.shutdown().await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        assert!(stream.is_closed());
+        assert_eq!(stream.queued_bytes(), 0);
+        let attempts = connector.attempts().len();
+        let err = stream.write(b"x").await.unwrap_err();
+        assert_eq!(category(&err), Some(ErrorCategory::Closed));
+        assert_eq!(connector.attempts().len(), attempts);
+    }
+}
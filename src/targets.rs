@@ -0,0 +1,250 @@
+//! Choosing next target when [RetryingStream](crate::RetryingStream) has more than one.
+
+use std::time::Duration;
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+use tokio::io::{Error, ErrorKind};
+use tokio::time::Instant;
+
+/// How next target is selected on reset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    /// Targets are tried in order. Broken connection is first retried on the same target, next
+    /// target is used only when connect fails. After the last target the first one (primary) is
+    /// tried again.
+    Failover,
+    /// Every reset moves to the next target in order.
+    RoundRobin,
+    /// Every reset picks random target different than current one.
+    Random,
+    /// Every reset picks random target with probability proportional to its weight.
+    /// There must be one weight for every target.
+    Weighted(Vec<u32>),
+}
+
+/// List of targets with [Strategy] choosing current one.
+#[derive(Debug, Clone)]
+pub struct TargetSet<T> {
+    targets: Vec<T>,
+    strategy: Strategy,
+    current: usize,
+    failback_after: Option<Duration>,
+    // since when we are connected to target other than primary
+    on_backup_since: Option<Instant>,
+}
+
+impl<T> TargetSet<T> {
+    /// Single target. Strategy doesn't matter.
+    pub fn single(target: T) -> Self {
+        Self {
+            targets: vec![target],
+            strategy: Strategy::Failover,
+            current: 0,
+            failback_after: None,
+            on_backup_since: None,
+        }
+    }
+
+    /// First target is the primary one and is used first.
+    ///
+    /// Return `InvalidInput` when `targets` is empty or [Strategy::Weighted] has wrong number of
+    /// weights or all of them are 0.
+    pub fn new(targets: Vec<T>, strategy: Strategy) -> Result<Self, Error> {
+        if targets.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "no targets"));
+        }
+        if let Strategy::Weighted(weights) = &strategy {
+            if weights.len() != targets.len() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "number of weights doesn't match number of targets",
+                ));
+            }
+            if weights.iter().all(|w| *w == 0) {
+                return Err(Error::new(ErrorKind::InvalidInput, "all weights are 0"));
+            }
+        }
+        Ok(Self {
+            targets,
+            strategy,
+            current: 0,
+            failback_after: None,
+            on_backup_since: None,
+        })
+    }
+
+    /// Go back to primary target once other target was used for `failback_after`.
+    ///
+    /// Stream connects to primary next to working connection to backup target and switches to
+    /// the new connection only when connect succeeded. When primary is still down backup
+    /// connection is kept and primary is tried again after another `failback_after`.
+    pub fn set_failback_after(&mut self, failback_after: Option<Duration>) {
+        self.failback_after = failback_after;
+    }
+
+    pub fn current(&self) -> &T {
+        &self.targets[self.current]
+    }
+
+    /// Index of current target. Primary has index 0.
+    pub fn current_index(&self) -> usize {
+        self.current
+    }
+
+    pub fn targets(&self) -> &[T] {
+        &self.targets
+    }
+
+    pub fn strategy(&self) -> &Strategy {
+        &self.strategy
+    }
+
+    // Move to next target. `was_connected` is false when connect to current target failed.
+    pub(crate) fn advance(&mut self, was_connected: bool) {
+        let len = self.targets.len();
+        if len == 1 {
+            return;
+        }
+        self.current = match &self.strategy {
+            Strategy::Failover if was_connected => self.current,
+            Strategy::Failover | Strategy::RoundRobin => (self.current + 1) % len,
+            Strategy::Random => {
+                let next = rand::thread_rng().gen_range(0..len - 1);
+                if next >= self.current {
+                    next + 1
+                } else {
+                    next
+                }
+            }
+            Strategy::Weighted(weights) => WeightedIndex::new(weights)
+                .expect("weights are validated in TargetSet::new")
+                .sample(&mut rand::thread_rng()),
+        };
+        if self.current == 0 {
+            self.on_backup_since = None;
+        }
+    }
+
+    pub(crate) fn on_connected(&mut self) {
+        if self.current != 0 && self.on_backup_since.is_none() {
+            self.on_backup_since = Some(Instant::now());
+        }
+    }
+
+    // When stream should go back to primary target.
+    pub(crate) fn failback_at(&self) -> Option<Instant> {
+        Some(self.on_backup_since? + self.failback_after?)
+    }
+
+    pub(crate) fn primary(&self) -> &T {
+        &self.targets[0]
+    }
+
+    // Connect to primary failed, stay on backup for another failback_after
+    pub(crate) fn on_failback_failed(&mut self) {
+        self.on_backup_since = Some(Instant::now());
+    }
+
+    pub(crate) fn select_primary(&mut self) {
+        self.current = 0;
+        self.on_backup_since = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(strategy: Strategy) -> TargetSet<u32> {
+        TargetSet::new(vec![0, 1, 2], strategy).unwrap()
+    }
+
+    #[test]
+    fn failover_moves_only_after_failed_connect() {
+        let mut targets = set(Strategy::Failover);
+        targets.advance(true);
+        assert_eq!(*targets.current(), 0);
+        for expected in [1, 2, 0, 1] {
+            targets.advance(false);
+            assert_eq!(*targets.current(), expected);
+        }
+        targets.advance(true);
+        assert_eq!(*targets.current(), 1);
+    }
+
+    #[test]
+    fn round_robin_moves_on_every_reset() {
+        let mut targets = set(Strategy::RoundRobin);
+        for (was_connected, expected) in [(true, 1), (false, 2), (true, 0)] {
+            targets.advance(was_connected);
+            assert_eq!(*targets.current(), expected);
+        }
+    }
+
+    #[test]
+    fn random_never_stays() {
+        let mut targets = set(Strategy::Random);
+        let mut seen = [false; 3];
+        for _ in 0..100 {
+            let before = targets.current_index();
+            targets.advance(true);
+            assert_ne!(targets.current_index(), before);
+            seen[targets.current_index()] = true;
+        }
+        assert_eq!(seen, [true; 3]);
+    }
+
+    #[test]
+    fn weighted_skips_zero_weight() {
+        let mut targets = set(Strategy::Weighted(vec![1, 0, 3]));
+        for _ in 0..100 {
+            targets.advance(false);
+            assert_ne!(*targets.current(), 1);
+        }
+    }
+
+    #[test]
+    fn invalid_sets_are_rejected() {
+        let invalid = [
+            TargetSet::<u32>::new(vec![], Strategy::Failover),
+            TargetSet::new(vec![0, 1], Strategy::Weighted(vec![1])),
+            TargetSet::new(vec![0, 1], Strategy::Weighted(vec![0, 0])),
+        ];
+        for res in invalid {
+            assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn single_target_stays() {
+        let mut targets = TargetSet::single(7);
+        targets.advance(false);
+        assert_eq!(*targets.current(), 7);
+    }
+
+    #[test]
+    fn failback_only_from_backup() {
+        let mut targets = set(Strategy::Failover);
+        targets.set_failback_after(Some(Duration::from_secs(60)));
+        targets.on_connected();
+        assert_eq!(targets.failback_at(), None);
+
+        targets.advance(false);
+        assert_eq!(targets.failback_at(), None);
+        let before = Instant::now();
+        targets.on_connected();
+        let failback_at = targets.failback_at().unwrap();
+        assert!(failback_at >= before + Duration::from_secs(60));
+        // reconnect to the same backup doesn't restart the period
+        targets.advance(true);
+        targets.on_connected();
+        assert_eq!(targets.failback_at(), Some(failback_at));
+
+        targets.on_failback_failed();
+        assert!(targets.failback_at().unwrap() >= failback_at);
+        targets.select_primary();
+        assert_eq!(targets.current_index(), 0);
+        assert_eq!(targets.failback_at(), None);
+    }
+}
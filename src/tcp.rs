@@ -0,0 +1,518 @@
+//! TCP flavour of [RetryingStream]: [TcpConnector] and [RetryingTcpStream].
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::future::Future;
+use std::net::{Shutdown, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use socket2::SockRef;
+use tokio::io::{Error, ErrorKind, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::builder::TcpStreamBuilder;
+use crate::connector::Connector;
+use crate::event::Event;
+use crate::resolve::{Resolver, TokioResolver};
+use crate::sockopt;
+use crate::stream::{ConnectionState, RetryingStream};
+use crate::targets::Strategy;
+
+/// Holding settings [TcpStreamSettings]
+///
+/// Options are applied to every new socket before it connects. `None` leaves system default.
+/// Options not available on current platform make connect fail with `Unsupported` error.
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Default)]
+pub struct TcpStreamSettings {
+    pub nodelay: bool,
+    pub keepalive: Option<Duration>,
+    /// Time between keepalive probes. Used only with `keepalive`.
+    pub keepalive_interval: Option<Duration>,
+    /// Number of unanswered keepalive probes before connection is dropped. Used only with
+    /// `keepalive`.
+    pub keepalive_retries: Option<u32>,
+    /// Abort connect attempt that takes longer with `TimedOut` error. Include name resolution.
+    pub connect_timeout: Option<Duration>,
+    /// When set, `connect_timeout` is doubled after each consecutive failed attempt up to this
+    /// value.
+    pub connect_timeout_max: Option<Duration>,
+    /// `SO_SNDBUF`
+    pub send_buffer_size: Option<usize>,
+    /// `SO_RCVBUF`
+    pub recv_buffer_size: Option<usize>,
+    /// `IP_TTL`, or `IPV6_UNICAST_HOPS` for IPv6 peers.
+    pub ttl: Option<u32>,
+    /// `SO_LINGER`
+    pub linger: Option<Duration>,
+    /// `IP_TOS`, or `IPV6_TCLASS` for IPv6 peers.
+    pub tos: Option<u32>,
+    /// `TCP_USER_TIMEOUT`, Linux only.
+    pub user_timeout: Option<Duration>,
+    /// `SO_MARK`, Linux only.
+    pub mark: Option<u32>,
+    /// `TCP_QUICKACK`, Linux only. Set once after connect, kernel may clear it later.
+    pub quickack: Option<bool>,
+    /// Local address socket is bound to before connect.
+    pub bind_addr: Option<SocketAddr>,
+}
+
+impl TcpStreamSettings {
+    // Timeout for attempt after `failed` consecutive failures
+    fn timeout_for_attempt(&self, failed: u32) -> Option<Duration> {
+        let timeout = self.connect_timeout?;
+        match self.connect_timeout_max {
+            None => Some(timeout),
+            Some(max) => Some(
+                2u32.checked_pow(failed)
+                    .and_then(|mul| timeout.checked_mul(mul))
+                    .unwrap_or(max)
+                    .min(max),
+            ),
+        }
+    }
+}
+
+/// Like TcpStream but pollable after Error.
+pub type RetryingTcpStream = RetryingStream<TcpConnector>;
+
+/// [Event] emitted by [RetryingTcpStream].
+pub type TcpEvent = Event<TcpTarget, SocketAddr>;
+
+/// Where [TcpConnector] connects to.
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub enum TcpTarget {
+    /// Fixed address.
+    Addr(SocketAddr),
+    /// Host name resolved with [Resolver] before every connect.
+    Host { host: String, port: u16 },
+}
+
+impl fmt::Display for TcpTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpTarget::Addr(addr) => addr.fmt(f),
+            TcpTarget::Host { host, port } => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl From<SocketAddr> for TcpTarget {
+    fn from(addr: SocketAddr) -> Self {
+        TcpTarget::Addr(addr)
+    }
+}
+
+/// Parse `ip:port` into [TcpTarget::Addr] and `host:port` into [TcpTarget::Host]. IPv6 address
+/// has to be in brackets, e.g. `[::1]:80`.
+impl FromStr for TcpTarget {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(TcpTarget::Addr(addr));
+        }
+        let invalid = || Error::new(ErrorKind::InvalidInput, "invalid host:port target");
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse().map_err(|_| invalid())?;
+        // unbracketed IPv6 address, e.g. `::1` would be host `:` with port 1
+        if host.is_empty() || host.contains(':') {
+            return Err(invalid());
+        }
+        Ok(TcpTarget::Host {
+            host: host.to_owned(),
+            port,
+        })
+    }
+}
+
+/// [Connector] opening [TcpStream] and applying [TcpStreamSettings] to every new socket.
+pub struct TcpConnector {
+    settings: TcpStreamSettings,
+    pub(crate) resolver: Arc<dyn Resolver>,
+    // peer of last established connection
+    last_addr: Option<SocketAddr>,
+    // connect attempts since last established connection
+    failed: u32,
+}
+
+impl TcpConnector {
+    pub fn new(settings: TcpStreamSettings) -> Self {
+        Self {
+            settings,
+            resolver: Arc::new(TokioResolver),
+            last_addr: None,
+            failed: 0,
+        }
+    }
+
+    pub fn settings(&self) -> &TcpStreamSettings {
+        &self.settings
+    }
+
+    /// Set resolver used for [TcpTarget::Host]. Default is [TokioResolver].
+    pub fn set_resolver<R: Resolver + 'static>(&mut self, resolver: R) {
+        self.resolver = Arc::new(resolver);
+    }
+
+    /// Peer address of last established connection.
+    pub fn last_addr(&self) -> Option<SocketAddr> {
+        self.last_addr
+    }
+}
+
+impl Connector for TcpConnector {
+    type Target = TcpTarget;
+    type Transport = TcpStream;
+    type Future = Pin<Box<dyn Future<Output = Result<TcpStream, Error>> + Send>>;
+    type Addr = SocketAddr;
+
+    fn connect(&mut self, target: &TcpTarget) -> Self::Future {
+        let timeout = self.settings.timeout_for_attempt(self.failed);
+        self.failed = self.failed.saturating_add(1);
+
+        let connect = self.connect_target(target);
+        match timeout {
+            None => connect,
+            Some(timeout) => Box::pin(async move {
+                match tokio::time::timeout(timeout, connect).await {
+                    Ok(res) => res,
+                    Err(_) => Err(Error::new(ErrorKind::TimedOut, "connect timed out")),
+                }
+            }),
+        }
+    }
+
+    fn configure(&mut self, ts: &TcpStream) -> Result<(), Error> {
+        self.failed = 0;
+        self.last_addr = Some(ts.peer_addr()?);
+        Ok(())
+    }
+
+    fn addrs(&self, ts: &TcpStream) -> Result<(SocketAddr, SocketAddr), Error> {
+        Ok((ts.local_addr()?, ts.peer_addr()?))
+    }
+
+    /// Read with `SIOCOUTQ` on Linux and Android, `None` elsewhere.
+    fn unacked_bytes(&self, ts: &TcpStream) -> Option<usize> {
+        sockopt::unacked_bytes(ts)
+    }
+}
+
+impl TcpConnector {
+    fn connect_target(&self, target: &TcpTarget) -> <Self as Connector>::Future {
+        let settings = self.settings.clone();
+        match target {
+            TcpTarget::Addr(addr) => {
+                let addr = *addr;
+                Box::pin(async move { sockopt::connect(addr, &settings).await })
+            }
+            TcpTarget::Host { host, port } => {
+                let resolve = self.resolver.resolve(host, *port);
+                Box::pin(async move {
+                    let mut last_err = None;
+                    for addr in resolve.await? {
+                        match sockopt::connect(addr, &settings).await {
+                            Ok(ts) => return Ok(ts),
+                            Err(err) => last_err = Some(err),
+                        }
+                    }
+                    Err(last_err.unwrap_or_else(|| {
+                        Error::new(ErrorKind::InvalidInput, "could not resolve to any address")
+                    }))
+                })
+            }
+        }
+    }
+}
+
+impl TryFrom<TcpStream> for RetryingTcpStream {
+    type Error = Error;
+    fn try_from(tcp_stream: TcpStream) -> Result<Self, Self::Error> {
+        let settings = TcpStreamSettings {
+            nodelay: tcp_stream.nodelay()?,
+            keepalive: sockopt::get_keepalive(&tcp_stream)?,
+            ..Default::default()
+        };
+
+        let addr = tcp_stream.peer_addr()?;
+        let mut connector = TcpConnector::new(settings);
+        connector.last_addr = Some(addr);
+        Ok(RetryingStream::from_transport(
+            connector,
+            TcpTarget::Addr(addr),
+            tcp_stream,
+        ))
+    }
+}
+
+/// Implement creators
+impl RetryingTcpStream {
+    /// Start building stream with [TcpStreamBuilder].
+    pub fn builder() -> TcpStreamBuilder {
+        TcpStreamBuilder::new()
+    }
+
+    /// Create stream in ConnectFuture state. Connection is started on first poll.
+    pub fn connect_with_settings(addr: &SocketAddr, settings: TcpStreamSettings) -> Self {
+        RetryingStream::new(TcpConnector::new(settings), TcpTarget::Addr(*addr))
+    }
+
+    /// Create stream with many targets. See [Strategy] for how next one is selected.
+    pub fn connect_with_failover(
+        targets: Vec<TcpTarget>,
+        strategy: Strategy,
+        settings: TcpStreamSettings,
+    ) -> Result<Self, Error> {
+        RetryingStream::with_targets(TcpConnector::new(settings), targets, strategy)
+    }
+
+    /// Create stream connecting to `host:port`.
+    ///
+    /// Host name is resolved again on every reconnect. Use
+    /// [set_resolver](TcpConnector::set_resolver) on [connector_mut](RetryingStream::connector_mut)
+    /// to change how.
+    pub fn connect_host(target: &str, settings: TcpStreamSettings) -> Result<Self, Error> {
+        Ok(RetryingStream::new(
+            TcpConnector::new(settings),
+            target.parse()?,
+        ))
+    }
+
+    /// # Panics
+    /// Like [from_std](tokio::net::TcpStream::from_std) this function panics when called outside of
+    /// tokio runtime.
+    pub fn from_std(stream: std::net::TcpStream) -> Result<Self, Error> {
+        let tokio_tcp_stream = TcpStream::from_std(stream)?;
+        Self::try_from(tokio_tcp_stream)
+    }
+}
+
+/// Reimplement of methods from [TcpStream]
+/// The main differences are:
+/// - `tokio::io::ErrorKind::NotConnected` is returned when inner state is in ConnectFuture and
+///   there is no other way to get response.
+/// - poll methods will try first go from ConnectFuture -> [TcpStream]
+///
+/// For more documentation go to [TcpStream] doc.
+///
+/// [TcpStream]:tokio::net::TcpStream
+impl RetryingTcpStream {
+    pub fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let ts = ready!(self.poll_connected(cx))?;
+        let res = ready!(ts.poll_read_ready(cx));
+        Poll::Ready(self.call_reset_if_io_is_closed2(res))
+    }
+
+    pub fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let ts = ready!(self.poll_connected(cx))?;
+        let res = ready!(ts.poll_write_ready(cx));
+        Poll::Ready(self.call_reset_if_io_is_closed2(res))
+    }
+
+    pub fn poll_peek(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<usize, Error>> {
+        let ts = ready!(self.poll_connected(cx))?;
+        let res = ready!(ts.poll_peek(cx, buf));
+        Poll::Ready(self.call_reset_if_io_is_closed2(res))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.get_ref()?.local_addr()
+    }
+
+    /// Return address of connected peer.
+    ///
+    /// When not connected return [current target](RetryingStream::target) address or, for host
+    /// name targets, address used by last connection.
+    pub fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        match (&self.state, self.targets.current()) {
+            (ConnectionState::Connected(ts), _) => ts.peer_addr(),
+            (_, TcpTarget::Addr(addr)) => Ok(*addr),
+            (_, TcpTarget::Host { .. }) => self
+                .connector
+                .last_addr
+                .ok_or_else(|| self.not_connected_error()),
+        }
+    }
+
+    /// Return value of current connection, or recorded one when not connected.
+    pub fn nodelay(&self) -> Result<bool, Error> {
+        match self.get_ref() {
+            Ok(ts) => ts.nodelay(),
+            Err(_) => Ok(self.connector.settings.nodelay),
+        }
+    }
+
+    /// Like all setters record `nodelay` in [TcpStreamSettings], see
+    /// [set_tcp_settings](RetryingTcpStream::set_tcp_settings).
+    pub fn set_nodelay(&mut self, nodelay: bool) -> Result<(), Error> {
+        self.set_tcp_settings(TcpStreamSettings {
+            nodelay,
+            ..self.connector.settings.clone()
+        })
+    }
+
+    /// Shut down the read, write, or both halves of this connection.
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        SockRef::from(self.get_ref()?).shutdown(how)
+    }
+
+    /// Return value of current connection, or recorded one when not connected. Kernel keeps
+    /// whole seconds, so it can differ from value that was set.
+    pub fn keepalive(&self) -> Result<Option<Duration>, Error> {
+        match self.get_ref() {
+            Ok(ts) => sockopt::get_keepalive(ts),
+            Err(_) => Ok(self.connector.settings.keepalive),
+        }
+    }
+
+    /// Like all setters record `keepalive` in [TcpStreamSettings], see
+    /// [set_tcp_settings](RetryingTcpStream::set_tcp_settings).
+    pub fn set_keepalive(&mut self, keepalive: Option<Duration>) -> Result<(), Error> {
+        self.set_tcp_settings(TcpStreamSettings {
+            keepalive,
+            ..self.connector.settings.clone()
+        })
+    }
+}
+
+/// Implement additional methods
+impl RetryingTcpStream {
+    /// Record desired settings and apply them to current connection, if there is one.
+    ///
+    /// Settings are re-applied to every new connection, so they survive reconnect. They are
+    /// recorded even when applying to current connection fails. `bind_addr` and buffer sizes may
+    /// have no effect on already connected socket. Option changed from `Some` to `None`, other
+    /// than `keepalive`, keeps its value on current connection; new connections get system
+    /// default.
+    pub fn set_tcp_settings(&mut self, tcp_settings: TcpStreamSettings) -> Result<(), Error> {
+        self.connector.settings = tcp_settings;
+        match &self.state {
+            ConnectionState::Connected(ts) => sockopt::apply(
+                &self.connector.settings,
+                SockRef::from(ts),
+                ts.local_addr()?.is_ipv6(),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Desired settings, applied to every new connection.
+    pub fn tcp_settings(&self) -> &TcpStreamSettings {
+        &self.connector.settings
+    }
+
+    /// Settings read back from current connection.
+    ///
+    /// Fields that are not socket options (timeouts, `bind_addr`) are copied from
+    /// [tcp_settings](RetryingTcpStream::tcp_settings). Options not available on current platform
+    /// are `None`. Return `NotConnected` when not connected.
+    pub fn live_tcp_settings(&self) -> Result<TcpStreamSettings, Error> {
+        sockopt::read(self.get_ref()?, &self.connector.settings)
+    }
+
+    /// return true if RetryingTcpStream represent [TcpStream](tokio::net::TcpStream) at this
+    /// moment.
+    ///
+    /// This can change after calling any `poll*()` method when that function returned error.
+    pub fn is_in_tcp_state(&self) -> bool {
+        self.is_connected()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_target() {
+        let addr: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        assert_eq!("127.0.0.1:80".parse::<TcpTarget>().unwrap(), addr.into());
+        let addr: SocketAddr = "[::1]:80".parse().unwrap();
+        assert_eq!("[::1]:80".parse::<TcpTarget>().unwrap(), addr.into());
+        assert_eq!(
+            "example.com:443".parse::<TcpTarget>().unwrap(),
+            TcpTarget::Host {
+                host: "example.com".to_owned(),
+                port: 443
+            }
+        );
+        for invalid in [
+            "::1",
+            "fe80::1:80",
+            "example.com",
+            ":80",
+            "example.com:http",
+            "",
+        ] {
+            let err = invalid.parse::<TcpTarget>().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn connect_timeout_doubles_up_to_max() {
+        let mut settings = TcpStreamSettings::default();
+        assert_eq!(settings.timeout_for_attempt(0), None);
+        settings.connect_timeout = Some(Duration::from_secs(1));
+        assert_eq!(
+            settings.timeout_for_attempt(5),
+            Some(Duration::from_secs(1))
+        );
+
+        settings.connect_timeout_max = Some(Duration::from_secs(5));
+        let timeouts: Vec<_> = (0..5)
+            .map(|failed| settings.timeout_for_attempt(failed).unwrap().as_secs())
+            .collect();
+        assert_eq!(timeouts, [1, 2, 4, 5, 5]);
+        // multiplier and multiplication overflow give max
+        for failed in [31, 32, u32::MAX] {
+            assert_eq!(
+                settings.timeout_for_attempt(failed),
+                Some(Duration::from_secs(5))
+            );
+        }
+        settings.connect_timeout = Some(Duration::MAX / 2);
+        settings.connect_timeout_max = Some(Duration::MAX);
+        assert_eq!(settings.timeout_for_attempt(2), Some(Duration::MAX));
+    }
+
+    // Never resolves
+    struct Stuck;
+
+    impl Resolver for Stuck {
+        fn resolve(&self, _host: &str, _port: u16) -> crate::resolve::ResolveFuture {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timed_out_connect_resets() {
+        use tokio::io::AsyncWriteExt;
+
+        let mut stream = RetryingTcpStream::builder()
+            .host("stuck.test:80")
+            .resolver(Stuck)
+            .connect_timeout(Duration::from_secs(1))
+            .connect_timeout_max(Duration::from_secs(4))
+            .build()
+            .unwrap();
+        let mut timeouts = Vec::new();
+        for _ in 0..4 {
+            let start = tokio::time::Instant::now();
+            let err = stream.write(b"x").await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            let err = crate::RetryingError::from_io(&err).unwrap();
+            assert_eq!(err.category(), crate::ErrorCategory::Connect);
+            timeouts.push(start.elapsed().as_secs());
+        }
+        assert_eq!(timeouts, [1, 2, 4, 4]);
+        assert!(!stream.has_given_up());
+    }
+}
@@ -0,0 +1,597 @@
+// Recording what happens to the stream: Stats, optional metrics and tracing spans.
+
+use std::fmt;
+use std::time::Duration;
+
+use tokio::io::Error;
+
+use crate::stats::Stats;
+
+// Span of current connection, entered while connect future and handshake are polled
+#[cfg(feature = "tracing")]
+pub(crate) type Span = tracing::Span;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Entered;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn enter(&self) -> Entered {
+        Entered
+    }
+}
+
+pub(crate) struct Telemetry {
+    pub(crate) stats: Stats,
+    pub(crate) name: Option<String>,
+    #[cfg(feature = "metrics")]
+    metrics: metered::Metrics,
+    #[cfg(feature = "tracing")]
+    spans: traced::Spans,
+}
+
+impl Telemetry {
+    pub(crate) fn new() -> Self {
+        Self {
+            stats: Stats::default(),
+            name: None,
+            #[cfg(feature = "metrics")]
+            metrics: metered::Metrics::default(),
+            #[cfg(feature = "tracing")]
+            spans: traced::Spans::default(),
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    pub(crate) fn span(&self) -> Span {
+        self.spans.connection.clone()
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn span(&self) -> Span {
+        Span
+    }
+
+    pub(crate) fn on_connect<T: fmt::Display>(&mut self, target: &T, attempt: u32) {
+        #[cfg(feature = "tracing")]
+        self.spans
+            .on_connect(self.name.as_deref(), target, attempt, &self.stats);
+        self.stats.on_connect();
+        #[cfg(feature = "metrics")]
+        self.metrics.on_connect(self.name.as_deref(), target);
+        #[cfg(not(any(feature = "metrics", feature = "tracing")))]
+        let _ = target;
+        #[cfg(not(feature = "tracing"))]
+        let _ = attempt;
+    }
+
+    // Stream was created from connected transport, `addrs` are its local and peer address
+    pub(crate) fn on_adopted<T: fmt::Display, A: fmt::Debug>(
+        &mut self,
+        target: &T,
+        addrs: Option<(&A, &A)>,
+    ) {
+        #[cfg(feature = "metrics")]
+        self.metrics.on_adopted(self.name.as_deref(), target);
+        #[cfg(feature = "tracing")]
+        self.spans
+            .on_adopted(self.name.as_deref(), target, addrs, &self.stats);
+        #[cfg(not(any(feature = "metrics", feature = "tracing")))]
+        let _ = target;
+        #[cfg(not(feature = "tracing"))]
+        let _ = addrs;
+    }
+
+    pub(crate) fn set_name(&mut self, name: String) {
+        #[cfg(feature = "metrics")]
+        self.metrics.on_renamed(&name);
+        #[cfg(feature = "tracing")]
+        self.spans.on_renamed(&name);
+        self.name = Some(name);
+    }
+
+    pub(crate) fn on_connected<A: fmt::Debug>(&mut self, local: &A, peer: &A) {
+        self.stats.on_connected();
+        #[cfg(feature = "metrics")]
+        self.metrics.on_connected();
+        #[cfg(feature = "tracing")]
+        self.spans.on_connected(local, peer);
+        #[cfg(not(feature = "tracing"))]
+        let _ = (local, peer);
+    }
+
+    pub(crate) fn on_failure(&mut self, err: &Error, was_connected: bool) {
+        self.stats.on_failure(err, was_connected);
+        #[cfg(feature = "metrics")]
+        self.metrics.on_failure(err, was_connected);
+        #[cfg(feature = "tracing")]
+        self.spans.on_failure(err, was_connected, &self.stats);
+    }
+
+    pub(crate) fn on_backoff(&mut self, delay: Duration) {
+        #[cfg(feature = "tracing")]
+        self.spans.on_backoff(delay);
+        #[cfg(not(feature = "tracing"))]
+        let _ = delay;
+    }
+
+    pub(crate) fn on_gave_up(&mut self, attempts: u32) {
+        #[cfg(feature = "tracing")]
+        self.spans.on_gave_up(attempts);
+        #[cfg(not(feature = "tracing"))]
+        let _ = attempts;
+    }
+
+    pub(crate) fn on_closed(&mut self) {
+        #[cfg(feature = "metrics")]
+        self.metrics.on_closed();
+        #[cfg(feature = "tracing")]
+        self.spans.on_closed(&self.stats);
+    }
+
+    pub(crate) fn on_read(&mut self, n: usize) {
+        self.stats.on_read(n);
+        #[cfg(feature = "metrics")]
+        self.metrics.on_read(n);
+    }
+
+    pub(crate) fn on_write(&mut self, n: usize) {
+        self.stats.on_write(n);
+        #[cfg(feature = "metrics")]
+        self.metrics.on_write(n);
+    }
+}
+
+#[cfg(feature = "metrics")]
+mod metered {
+    use std::fmt;
+    use std::time::Instant;
+
+    use metrics::{counter, gauge, histogram, Counter, Gauge, Histogram, Label};
+    use tokio::io::Error;
+
+    // Handles labelled with stream name and current target
+    struct Handles {
+        labels: Vec<Label>,
+        connect_attempts: Counter,
+        connects: Counter,
+        connect_duration: Histogram,
+        connected: Gauge,
+        bytes_read: Counter,
+        bytes_written: Counter,
+    }
+
+    impl Handles {
+        fn new(labels: Vec<Label>) -> Self {
+            Self {
+                connect_attempts: counter!("retrying_stream_connect_attempts_total", labels.iter()),
+                connects: counter!("retrying_stream_connects_total", labels.iter()),
+                connect_duration: histogram!(
+                    "retrying_stream_connect_duration_seconds",
+                    labels.iter()
+                ),
+                connected: gauge!("retrying_stream_connected", labels.iter()),
+                bytes_read: counter!("retrying_stream_bytes_read_total", labels.iter()),
+                bytes_written: counter!("retrying_stream_bytes_written_total", labels.iter()),
+                labels,
+            }
+        }
+    }
+
+    #[derive(Default)]
+    pub(super) struct Metrics {
+        handles: Option<Handles>,
+        connect_started: Option<Instant>,
+        // value of connected gauge
+        connected: bool,
+    }
+
+    impl Metrics {
+        pub(super) fn on_connect<T: fmt::Display>(&mut self, name: Option<&str>, target: &T) {
+            self.set_connected(false);
+            let handles = self.handles_for(name, target);
+            handles.connect_attempts.increment(1);
+            self.connect_started = Some(Instant::now());
+        }
+
+        pub(super) fn on_adopted<T: fmt::Display>(&mut self, name: Option<&str>, target: &T) {
+            self.handles_for(name, target);
+            self.set_connected(true);
+        }
+
+        // Move handles to new stream label, gauge goes with them
+        pub(super) fn on_renamed(&mut self, name: &str) {
+            let connected = self.connected;
+            let labels = match &self.handles {
+                Some(handles) => handles.labels.clone(),
+                None => return,
+            };
+            self.set_connected(false);
+            let labels = labels
+                .into_iter()
+                .map(|label| match label.key() {
+                    "stream" => Label::new("stream", name.to_owned()),
+                    _ => label,
+                })
+                .collect();
+            self.handles = Some(Handles::new(labels));
+            self.set_connected(connected);
+        }
+
+        // Handles labelled with `name` and `target`, created when labels changed
+        fn handles_for<T: fmt::Display>(&mut self, name: Option<&str>, target: &T) -> &Handles {
+            let labels = vec![
+                Label::new("stream", name.unwrap_or("").to_owned()),
+                Label::new("target", target.to_string()),
+            ];
+            if self.handles.as_ref().map(|h| &h.labels) != Some(&labels) {
+                self.set_connected(false);
+                self.handles = Some(Handles::new(labels));
+            }
+            self.handles.as_ref().expect("handles were just created")
+        }
+
+        fn set_connected(&mut self, connected: bool) {
+            self.connected = connected;
+            if let Some(handles) = &self.handles {
+                handles.connected.set(if connected { 1.0 } else { 0.0 });
+            }
+        }
+
+        pub(super) fn on_connected(&mut self) {
+            self.set_connected(true);
+            if let Some(handles) = &self.handles {
+                handles.connects.increment(1);
+                if let Some(started) = self.connect_started.take() {
+                    handles
+                        .connect_duration
+                        .record(started.elapsed().as_secs_f64());
+                }
+            }
+        }
+
+        pub(super) fn on_failure(&mut self, err: &Error, was_connected: bool) {
+            self.set_connected(false);
+            let handles = match &self.handles {
+                Some(handles) => handles,
+                None => return,
+            };
+            let mut labels = handles.labels.clone();
+            labels.push(Label::new("kind", format!("{:?}", err.kind())));
+            if was_connected {
+                counter!("retrying_stream_disconnects_total", labels).increment(1);
+            } else {
+                counter!("retrying_stream_connect_failures_total", labels).increment(1);
+            }
+        }
+
+        pub(super) fn on_closed(&mut self) {
+            self.set_connected(false);
+        }
+
+        pub(super) fn on_read(&mut self, n: usize) {
+            if let Some(handles) = &self.handles {
+                handles.bytes_read.increment(n as u64);
+            }
+        }
+
+        pub(super) fn on_write(&mut self, n: usize) {
+            if let Some(handles) = &self.handles {
+                handles.bytes_written.increment(n as u64);
+            }
+        }
+    }
+
+    impl Drop for Metrics {
+        fn drop(&mut self) {
+            self.on_closed();
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+mod traced {
+    use std::fmt;
+    use std::time::Duration;
+
+    use tokio::io::Error;
+    use tracing::{debug, error, field, info, info_span, warn, Span};
+
+    use crate::stats::Stats;
+
+    // Span of the whole stream and of current connection, child of the first one
+    pub(super) struct Spans {
+        stream: Span,
+        pub(super) connection: Span,
+        // connection span got connected, so bytes transferred belong to it
+        connected: bool,
+    }
+
+    impl Default for Spans {
+        fn default() -> Self {
+            Self {
+                stream: Span::none(),
+                connection: Span::none(),
+                connected: false,
+            }
+        }
+    }
+
+    impl Spans {
+        pub(super) fn on_connect<T: fmt::Display>(
+            &mut self,
+            name: Option<&str>,
+            target: &T,
+            attempt: u32,
+            stats: &Stats,
+        ) {
+            self.start_connection(name, target, attempt, stats);
+            debug!(parent: &self.connection, "connecting");
+        }
+
+        pub(super) fn on_adopted<T: fmt::Display, A: fmt::Debug>(
+            &mut self,
+            name: Option<&str>,
+            target: &T,
+            addrs: Option<(&A, &A)>,
+            stats: &Stats,
+        ) {
+            self.start_connection(name, target, 0, stats);
+            if let Some((local, peer)) = addrs {
+                self.connection.record("local", field::debug(local));
+                self.connection.record("peer", field::debug(peer));
+            }
+            self.connected = true;
+            info!(parent: &self.connection, "created from connected transport");
+        }
+
+        pub(super) fn on_renamed(&mut self, name: &str) {
+            self.stream.record("name", name);
+        }
+
+        // Open stream span if there is none yet and new connection span in it
+        fn start_connection<T: fmt::Display>(
+            &mut self,
+            name: Option<&str>,
+            target: &T,
+            attempt: u32,
+            stats: &Stats,
+        ) {
+            if self.stream.is_none() {
+                self.stream = info_span!(
+                    parent: None,
+                    "retrying_stream",
+                    name = name.unwrap_or_default()
+                );
+            }
+            self.end_connection(stats);
+            self.connection = info_span!(
+                parent: &self.stream,
+                "connection",
+                %target,
+                attempt,
+                local = field::Empty,
+                peer = field::Empty,
+                bytes_read = field::Empty,
+                bytes_written = field::Empty,
+            );
+        }
+
+        pub(super) fn on_connected<A: fmt::Debug>(&mut self, local: &A, peer: &A) {
+            self.connection.record("local", field::debug(local));
+            self.connection.record("peer", field::debug(peer));
+            self.connected = true;
+            info!(parent: &self.connection, "connected");
+        }
+
+        pub(super) fn on_failure(&mut self, err: &Error, was_connected: bool, stats: &Stats) {
+            let kind = err.kind();
+            if was_connected {
+                warn!(parent: &self.connection, error = %err, ?kind, "disconnected");
+            } else {
+                warn!(parent: &self.connection, error = %err, ?kind, "connect failed");
+            }
+            self.end_connection(stats);
+        }
+
+        pub(super) fn on_backoff(&mut self, delay: Duration) {
+            debug!(parent: &self.stream, ?delay, "backing off");
+        }
+
+        pub(super) fn on_gave_up(&mut self, attempts: u32) {
+            error!(parent: &self.stream, attempts, "gave up reconnecting");
+        }
+
+        pub(super) fn on_closed(&mut self, stats: &Stats) {
+            self.end_connection(stats);
+            info!(parent: &self.stream, "closed");
+        }
+
+        // Record bytes transferred and close connection span
+        fn end_connection(&mut self, stats: &Stats) {
+            if self.connected {
+                self.connection
+                    .record("bytes_read", stats.connection_bytes_read);
+                self.connection
+                    .record("bytes_written", stats.connection_bytes_written);
+            }
+            self.connection = Span::none();
+            self.connected = false;
+        }
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod metrics_tests {
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+
+    use metrics::{
+        Counter, Gauge, GaugeFn, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit,
+    };
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::RetryingTcpStream;
+
+    type Values = Arc<Mutex<HashMap<String, f64>>>;
+
+    // Records gauge values by their labels, other metrics are ignored
+    #[derive(Default)]
+    struct Gauges(Values);
+
+    struct Slot {
+        labels: String,
+        values: Values,
+    }
+
+    impl GaugeFn for Slot {
+        fn increment(&self, value: f64) {
+            *self
+                .values
+                .lock()
+                .unwrap()
+                .entry(self.labels.clone())
+                .or_default() += value;
+        }
+
+        fn decrement(&self, value: f64) {
+            self.increment(-value);
+        }
+
+        fn set(&self, value: f64) {
+            self.values
+                .lock()
+                .unwrap()
+                .insert(self.labels.clone(), value);
+        }
+    }
+
+    impl Recorder for Gauges {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, _: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::noop()
+        }
+
+        fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+            let labels: Vec<_> = key
+                .labels()
+                .map(|label| format!("{}={}", label.key(), label.value()))
+                .collect();
+            Gauge::from_arc(Arc::new(Slot {
+                labels: labels.join(","),
+                values: self.0.clone(),
+            }))
+        }
+
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_from_connected_transport_is_recorded() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let gauges = Gauges::default();
+        let values = gauges.0.clone();
+
+        let mut stream = metrics::with_local_recorder(&gauges, || {
+            let stream = RetryingTcpStream::try_from(tcp).unwrap();
+            assert_eq!(
+                values.lock().unwrap()[&format!("stream=,target={}", addr)],
+                1.0
+            );
+            stream
+        });
+        // gauge moves to new label
+        metrics::with_local_recorder(&gauges, || stream.set_name("db"));
+        let connected = values.lock().unwrap().clone();
+        assert_eq!(connected[&format!("stream=,target={}", addr)], 0.0);
+        assert_eq!(connected[&format!("stream=db,target={}", addr)], 1.0);
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tracing_tests {
+    use std::convert::TryFrom;
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+
+    use tokio::net::{TcpListener, TcpStream};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    use crate::RetryingTcpStream;
+
+    // span name and its fields
+    type Recorded = Vec<(&'static str, Vec<String>)>;
+
+    // Records span names with fields given when span was created or later
+    #[derive(Default)]
+    struct Spans(Arc<Mutex<Recorded>>);
+
+    struct Fields<'a>(&'a mut Vec<String>);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Spans {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut spans = self.0.lock().unwrap();
+            let mut fields = Vec::new();
+            span.record(&mut Fields(&mut fields));
+            spans.push((span.metadata().name(), fields));
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.0.lock().unwrap();
+            let index = span.into_u64() as usize - 1;
+            values.record(&mut Fields(&mut spans[index].1));
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, _: &Event<'_>) {}
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[tokio::test]
+    async fn stream_from_connected_transport_has_spans() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let subscriber = Spans::default();
+        let spans = subscriber.0.clone();
+
+        let _stream = tracing::subscriber::with_default(subscriber, || {
+            let mut stream = RetryingTcpStream::try_from(tcp).unwrap();
+            stream.set_name("db");
+            stream
+        });
+        let spans = spans.lock().unwrap();
+        let names: Vec<_> = spans.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["retrying_stream", "connection"]);
+        assert!(spans[0].1.contains(&"name=\"db\"".to_owned()));
+        let fields = &spans[1].1;
+        assert!(fields.contains(&format!("target={}", addr)));
+        assert!(fields.contains(&format!("peer={:?}", addr)));
+    }
+}
@@ -0,0 +1,374 @@
+//! Legacy implementation of [RetryingTcpStream] for tokio 0.1 and futures 0.1.
+//!
+//! Available with `tokio01` feature. It is kept for users that can't move to tokio 1 yet and is
+//! only maintained, new features are added to [crate::RetryingTcpStream].
+//!
+//! When you call `poll_read()` or `poll_write()` on [RetryingTcpStream]
+//! 1. If it is in [ConnectFuture] state -> will go to [TcpStream] state and go to 2.
+//! 2. If it is in [TcpStream] state -> will call requested method retrurning result:
+//!    - `Ok(_)` -> Normal poll result
+//!    - `Err(_)` -> Internal state is reset to [ConnectFuture] state. Next poll*() method will try connect.
+//!
+//! [RetryingTcpStream]: RetryingTcpStream
+//! [ConnectFuture]: tokio01::net::tcp::ConnectFuture
+//! [TcpStream]: tokio01::net::TcpStream
+
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::time::{Duration, Instant};
+
+use futures01::try_ready;
+use log::debug;
+use tokio01::io::{AsyncRead, AsyncWrite, Error};
+use tokio01::prelude::{Async, Future, Poll};
+use tokio01::timer::Delay;
+
+use crate::policy::{ConstantBackoff, ReconnectPolicy};
+use crate::DEFAULT_STABLE_PERIOD;
+
+/// Holding settings [TcpStreamSettings]
+#[derive(Hash, PartialEq, Eq, Clone)]
+pub struct TcpStreamSettings {
+    pub nodelay: bool,
+    pub keepalive: Option<Duration>,
+}
+
+// Handle connection state
+enum ConnectionState {
+    Backoff(Delay),
+    ConnectFuture(tokio01::net::tcp::ConnectFuture),
+    TcpStream(tokio01::net::TcpStream),
+    // terminal state after shutdown, TcpStream is kept so rest of data can be read
+    Closed(Option<tokio01::net::TcpStream>),
+}
+
+/// Like TcpStream but pollable after Error.
+pub struct RetryingTcpStream {
+    addr: std::net::SocketAddr,
+    settings: TcpStreamSettings,
+    state: ConnectionState,
+    policy: Box<dyn ReconnectPolicy>,
+    stable_period: Duration,
+    // number of reconnects since connection was last stable
+    attempt: u32,
+    // when current TcpStream was established
+    connected_at: Option<Instant>,
+}
+
+impl TryFrom<tokio01::net::TcpStream> for RetryingTcpStream {
+    type Error = Error;
+    fn try_from(tcp_stream: tokio01::net::TcpStream) -> Result<Self, Self::Error> {
+        let settings = TcpStreamSettings {
+            nodelay: tcp_stream.nodelay()?,
+            keepalive: tcp_stream.keepalive()?,
+        };
+
+        Ok(RetryingTcpStream {
+            addr: tcp_stream.peer_addr()?,
+            state: ConnectionState::TcpStream(tcp_stream),
+            settings,
+            policy: Box::new(ConstantBackoff::default()),
+            stable_period: DEFAULT_STABLE_PERIOD,
+            attempt: 0,
+            connected_at: Some(Instant::now()),
+        })
+    }
+}
+
+/// Implement creators
+impl RetryingTcpStream {
+    pub fn connect_with_settings(addr: &std::net::SocketAddr, settings: TcpStreamSettings) -> Self {
+        Self {
+            addr: *addr,
+            state: ConnectionState::ConnectFuture(tokio01::net::TcpStream::connect(addr)),
+            settings,
+            policy: Box::new(ConstantBackoff::default()),
+            stable_period: DEFAULT_STABLE_PERIOD,
+            attempt: 0,
+            connected_at: None,
+        }
+    }
+
+    pub fn from_std(
+        stream: std::net::TcpStream,
+        handle: &tokio01::reactor::Handle,
+    ) -> Result<Self, Error> {
+        let tokio_tcp_stream = tokio01::net::TcpStream::from_std(stream, handle)?;
+        Self::try_from(tokio_tcp_stream)
+    }
+}
+
+/// Reimplement of methods from [TcpStream]
+/// The main differences are:
+/// - `tokio01::io::ErrorKind::NotConnected` is returned when inner state is in [ConnectFuture] and
+///   there is no other way to get response.
+/// - poll methods will try first go from [ConnectFuture] -> [TcpStream]
+///
+/// For more documentation go to [TcpStream] doc.
+///
+/// [TcpStream]:tokio01::net::TcpStream
+/// [ConnectFuture]:tokio01::net::tcp::ConnectFuture
+impl RetryingTcpStream {
+    pub fn poll_read_ready(&mut self, mask: mio::Ready) -> Result<Async<mio::Ready>, Error> {
+        let ts = try_ready!(self.poll_into_tcp_stream());
+        let res = ts.poll_read_ready(mask);
+        self.call_reset_if_io_is_closed2(res)
+    }
+
+    pub fn poll_write_ready(&mut self) -> Result<Async<mio::Ready>, Error> {
+        let ts = try_ready!(self.poll_into_tcp_stream());
+        let res = ts.poll_write_ready();
+        self.call_reset_if_io_is_closed2(res)
+    }
+
+    pub fn poll_peek(&mut self, buf: &mut [u8]) -> Result<Async<usize>, Error> {
+        let ts = try_ready!(self.poll_into_tcp_stream());
+        let res = ts.poll_peek(buf);
+        self.call_reset_if_io_is_closed2(res)
+    }
+
+    pub fn local_addr(&self) -> Result<std::net::SocketAddr, Error> {
+        self.ref_tcp_stream()?.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> Result<std::net::SocketAddr, Error> {
+        match &self.state {
+            ConnectionState::TcpStream(ts) => {
+                let r = ts.peer_addr()?;
+                debug_assert_eq!(r, self.addr);
+                Ok(r)
+            }
+            _ => Ok(self.addr),
+        }
+    }
+
+    /// Return value of current connection, or recorded one when not connected.
+    pub fn nodelay(&self) -> Result<bool, Error> {
+        match self.ref_tcp_stream() {
+            Ok(ts) => ts.nodelay(),
+            Err(_) => Ok(self.settings.nodelay),
+        }
+    }
+
+    pub fn set_nodelay(&mut self, nodelay: bool) -> Result<(), Error> {
+        if let ConnectionState::TcpStream(ts) = &self.state {
+            ts.set_nodelay(nodelay)?;
+        }
+        self.settings.nodelay = nodelay;
+        Ok(())
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        self.ref_tcp_stream()?.shutdown(how)
+    }
+
+    /// Return value of current connection, or recorded one when not connected. Kernel keeps
+    /// whole seconds, so it can differ from value that was set.
+    pub fn keepalive(&self) -> Result<Option<Duration>, Error> {
+        match self.ref_tcp_stream() {
+            Ok(ts) => ts.keepalive(),
+            Err(_) => Ok(self.settings.keepalive),
+        }
+    }
+
+    /// Record `keepalive` for every new connection and apply it to current one, if there is
+    /// one.
+    pub fn set_keepalive(&mut self, keepalive: Option<Duration>) -> Result<(), Error> {
+        if let ConnectionState::TcpStream(ts) = &self.state {
+            ts.set_keepalive(keepalive)?;
+        }
+        self.settings.keepalive = keepalive;
+        Ok(())
+    }
+}
+
+/// Implement additional methods
+impl RetryingTcpStream {
+    pub fn set_tcp_settings(&mut self, tcp_settings: TcpStreamSettings) -> Result<(), Error> {
+        self.set_nodelay(tcp_settings.nodelay)?;
+        self.set_keepalive(tcp_settings.keepalive)?;
+
+        self.settings = tcp_settings;
+        Ok(())
+    }
+
+    /// Set policy used to delay reconnect attempts.
+    pub fn set_reconnect_policy<P: ReconnectPolicy + 'static>(&mut self, policy: P) {
+        self.policy = Box::new(policy);
+        self.attempt = 0;
+    }
+
+    /// Set how long connection has to stay up before reconnect policy is reset.
+    ///
+    /// Default is [DEFAULT_STABLE_PERIOD].
+    pub fn set_stable_period(&mut self, stable_period: Duration) {
+        self.stable_period = stable_period;
+    }
+
+    /// return true if RetryingTcpStream represent [TcpStream](tokio01::net::TcpStream) at this
+    /// moment.
+    ///
+    /// This can change after calling any `poll*()` method when that function returned error.
+    pub fn is_in_tcp_state(&self) -> bool {
+        matches!(self.state, ConnectionState::TcpStream(_))
+    }
+
+    /// return true after [shutdown](AsyncWrite::shutdown) was called. Closed stream never
+    /// reconnects.
+    pub fn is_closed(&self) -> bool {
+        matches!(self.state, ConnectionState::Closed(_))
+    }
+
+    fn ref_tcp_stream(&self) -> Result<&tokio01::net::TcpStream, Error> {
+        match &self.state {
+            ConnectionState::TcpStream(ts) => Ok(ts),
+            _ => Err(Error::from(tokio01::io::ErrorKind::NotConnected)),
+        }
+    }
+
+    // Return NotReady until ConnectionState is diffrent than TcpStream
+    fn poll_into_tcp_stream(&mut self) -> Poll<&mut tokio01::net::TcpStream, Error> {
+        loop {
+            match &mut self.state {
+                ConnectionState::Backoff(delay) => {
+                    try_ready!(delay.poll().map_err(Error::other));
+                    debug!("RetryingTcpStream => change state Backoff -> ConnectFuture");
+                    self.connect();
+                }
+                ConnectionState::ConnectFuture(cf) => {
+                    let tcp_s = match cf.poll() {
+                        Ok(Async::Ready(tcp_s)) => tcp_s,
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(err) => {
+                            self.reset();
+                            return Err(err);
+                        }
+                    };
+                    self.state = ConnectionState::TcpStream(tcp_s);
+                    self.connected_at = Some(Instant::now());
+                    self.set_tcp_settings(self.settings.clone())?;
+                    debug!("RetryingTcpStream => change state ConnectFuture -> TcpStream")
+                }
+                ConnectionState::TcpStream(_) => break,
+                ConnectionState::Closed(_) => {
+                    return Err(Error::new(
+                        tokio01::io::ErrorKind::BrokenPipe,
+                        "RetryingTcpStream was shut down",
+                    ));
+                }
+            }
+        }
+
+        match self.state {
+            ConnectionState::TcpStream(ref mut ts) => Ok(Async::Ready(ts)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn connect(&mut self) {
+        self.state = ConnectionState::ConnectFuture(tokio01::net::TcpStream::connect(&self.addr))
+    }
+
+    fn reset(&mut self) {
+        debug!("RetryinTcpStream => reset was called!");
+        if let Some(connected_at) = self.connected_at.take() {
+            if connected_at.elapsed() >= self.stable_period {
+                self.attempt = 0;
+                self.policy.reset();
+            }
+        }
+        self.attempt = self.attempt.saturating_add(1);
+
+        let delay = self.policy.next_delay(self.attempt);
+        if delay == Duration::from_secs(0) {
+            self.connect();
+        } else {
+            debug!("RetryingTcpStream => backoff for {:?}", delay);
+            self.state = ConnectionState::Backoff(Delay::new(Instant::now() + delay));
+        }
+    }
+
+    fn call_reset_if_io_is_closed2<T>(&mut self, res: Result<T, Error>) -> Result<T, Error> {
+        use tokio01::io::ErrorKind;
+        match res {
+            Ok(ok) => Ok(ok),
+            Err(err) => {
+                match err.kind() {
+                    ErrorKind::WouldBlock => (),
+                    _ => self.reset(),
+                };
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Read for RetryingTcpStream {
+    /// # Note
+    /// This is Async version of Read. It will panic outside of tash
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // after shutdown rest of data can still be read, but nothing is reconnected
+        if let ConnectionState::Closed(Some(ts)) = &mut self.state {
+            return ts.read(buf);
+        }
+        let ts = self.poll_into_tcp_stream()?;
+        let r = match ts {
+            Async::Ready(ts) => ts.read(buf),
+            Async::NotReady => Err(std::io::ErrorKind::WouldBlock.into()),
+        };
+
+        self.call_reset_if_io_is_closed2(r)
+    }
+}
+
+impl Write for RetryingTcpStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let ts = self.poll_into_tcp_stream()?;
+        let r = match ts {
+            Async::Ready(ts) => ts.write(buf),
+            Async::NotReady => Err(std::io::ErrorKind::WouldBlock.into()),
+        };
+
+        self.call_reset_if_io_is_closed2(r)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let ts = self.poll_into_tcp_stream()?;
+        let r = match ts {
+            Async::Ready(ts) => ts.flush(),
+            Async::NotReady => Err(std::io::ErrorKind::WouldBlock.into()),
+        };
+
+        self.call_reset_if_io_is_closed2(r)
+    }
+}
+
+// Logic is implemented inside Read and Write trait becouse we can't overwrite AsyncRead and
+// AsyncWrite for Box<RetryingTcpStream>
+// source: https://docs.rs/tokio-io/0.1.12/src/tokio_io/async_write.rs.html#149
+impl AsyncRead for RetryingTcpStream {}
+
+impl AsyncWrite for RetryingTcpStream {
+    /// Shut down TcpStream and go to closed state. When not connected, pending connect is
+    /// cancelled.
+    fn shutdown(&mut self) -> Poll<(), Error> {
+        let res = match &mut self.state {
+            ConnectionState::TcpStream(ts) => match ts.shutdown() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(())) => Ok(()),
+                Err(err) => Err(err),
+            },
+            ConnectionState::Closed(_) => return Ok(Async::Ready(())),
+            ConnectionState::Backoff(_) | ConnectionState::ConnectFuture(_) => Ok(()),
+        };
+
+        let state = std::mem::replace(&mut self.state, ConnectionState::Closed(None));
+        if let (ConnectionState::TcpStream(ts), Ok(_)) = (state, &res) {
+            self.state = ConnectionState::Closed(Some(ts));
+        }
+        self.connected_at = None;
+        debug!("RetryingTcpStream => change state to Closed");
+        res.map(Async::Ready)
+    }
+}
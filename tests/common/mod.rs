@@ -0,0 +1,120 @@
+// Helpers shared by integration tests, not every test uses all of them
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng;
+use socket2::SockRef;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+// Forwards connections to `target` and resets each of them after it forwarded random number of
+// bytes up to `max_bytes`, losing data in flight. Returns its address and number of broken
+// connections.
+pub async fn breaking_proxy(
+    target: SocketAddr,
+    max_bytes: usize,
+) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let breaks = Arc::new(AtomicUsize::new(0));
+    let counter = breaks.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut server = match TcpStream::connect(target).await {
+                Ok(server) => server,
+                Err(_) => continue,
+            };
+            let limit = rand::thread_rng().gen_range(max_bytes / 4..=max_bytes);
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                if forward(&mut client, &mut server, limit).await {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+                // both connections are reset, not closed
+                let _ = SockRef::from(&client).set_linger(Some(Duration::ZERO));
+                let _ = SockRef::from(&server).set_linger(Some(Duration::ZERO));
+            });
+        }
+    });
+    (addr, breaks)
+}
+
+// Copy both ways, return true when `limit` was reached
+async fn forward(client: &mut TcpStream, server: &mut TcpStream, limit: usize) -> bool {
+    let (mut client_read, mut client_write) = client.split();
+    let (mut server_read, mut server_write) = server.split();
+    let mut up = [0; 16 * 1024];
+    let mut down = [0; 16 * 1024];
+    let mut forwarded = 0;
+    while forwarded < limit {
+        let res = tokio::select! {
+            res = client_read.read(&mut up) => match res {
+                Ok(n) if n > 0 => server_write.write_all(&up[..n]).await.map(|()| n),
+                _ => return false,
+            },
+            res = server_read.read(&mut down) => match res {
+                Ok(n) if n > 0 => client_write.write_all(&down[..n]).await.map(|()| n),
+                _ => return false,
+            },
+        };
+        match res {
+            Ok(n) => forwarded += n,
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+// Forwards connections to `target` while it is up. Setting it down resets all connections and
+// new ones are closed right after accept.
+pub struct SwitchProxy {
+    pub addr: SocketAddr,
+    up: watch::Sender<bool>,
+}
+
+impl SwitchProxy {
+    pub async fn start(target: SocketAddr) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (up, up_rx) = watch::channel(true);
+        tokio::spawn(async move {
+            loop {
+                let (mut client, _) = listener.accept().await.unwrap();
+                let mut up_rx = up_rx.clone();
+                if !*up_rx.borrow_and_update() {
+                    continue;
+                }
+                let mut server = match TcpStream::connect(target).await {
+                    Ok(server) => server,
+                    Err(_) => continue,
+                };
+                tokio::spawn(async move {
+                    tokio::select! {
+                        _ = tokio::io::copy_bidirectional(&mut client, &mut server) => {}
+                        _ = up_rx.wait_for(|up| !up) => {}
+                    }
+                    let _ = SockRef::from(&client).set_linger(Some(Duration::ZERO));
+                    let _ = SockRef::from(&server).set_linger(Some(Duration::ZERO));
+                });
+            }
+        });
+        Self { addr, up }
+    }
+
+    pub fn set_up(&self, up: bool) {
+        self.up.send_replace(up);
+    }
+}
+
+// Data that tells where it was cut or reordered
+pub fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u32).wrapping_mul(31).wrapping_add(seed as u32) as u8 ^ (i >> 10) as u8)
+        .collect()
+}
@@ -0,0 +1,66 @@
+use std::io::ErrorKind;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_retrying_tcpstream::{EofPolicy, RetryingError, RetryingTcpStream};
+
+// Accept connection, send `n` and close it gracefully
+async fn serve(listener: &TcpListener, n: u8) {
+    let (mut conn, _) = listener.accept().await.unwrap();
+    conn.write_all(&[n]).await.unwrap();
+    conn.shutdown().await.unwrap();
+}
+
+fn stream(listener: &TcpListener, eof_policy: EofPolicy) -> RetryingTcpStream {
+    RetryingTcpStream::builder()
+        .target(listener.local_addr().unwrap())
+        .eof_policy(eof_policy)
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn return_policy_reads_end_of_stream() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut stream = stream(&listener, EofPolicy::Return);
+    let (res, ()) = tokio::join!(stream.read_u8(), serve(&listener, 0));
+    assert_eq!(res.unwrap(), 0);
+
+    let mut buf = [0; 1];
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    assert!(stream.is_connected());
+    let stats = stream.stats();
+    assert_eq!((stats.connects, stats.disconnects), (1, 0));
+}
+
+#[tokio::test]
+async fn reconnect_policy_continues_on_next_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut stream = stream(&listener, EofPolicy::Reconnect);
+    let (res, ()) = tokio::join!(stream.read_u8(), serve(&listener, 0));
+    assert_eq!(res.unwrap(), 0);
+
+    // end of first connection is not seen by caller
+    let (res, ()) = tokio::join!(stream.read_u8(), serve(&listener, 1));
+    assert_eq!(res.unwrap(), 1);
+    let stats = stream.stats();
+    assert_eq!((stats.connects, stats.disconnects), (2, 1));
+}
+
+#[tokio::test]
+async fn error_policy_returns_reset_and_reconnects() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut stream = stream(&listener, EofPolicy::Error);
+    let (res, ()) = tokio::join!(stream.read_u8(), serve(&listener, 0));
+    assert_eq!(res.unwrap(), 0);
+
+    let err = stream.read(&mut [0; 1]).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    assert!(RetryingError::from_io(&err).is_some());
+    assert!(!stream.is_connected());
+
+    let (res, ()) = tokio::join!(stream.read_u8(), serve(&listener, 1));
+    assert_eq!(res.unwrap(), 1);
+    assert_eq!(stream.stats().connects, 2);
+}
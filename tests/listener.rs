@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_retrying_tcpstream::{RetryingTcpStream, Session, SessionListener};
+
+mod common;
+
+use common::SwitchProxy;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn expired_session_is_aborted() {
+    let mut listener = SessionListener::bind("127.0.0.1:0").await.unwrap();
+    listener.set_session_timeout(Duration::from_millis(200));
+    let proxy = SwitchProxy::start(listener.local_addr().unwrap()).await;
+
+    let stream = RetryingTcpStream::builder()
+        .target(proxy.addr)
+        .build()
+        .unwrap();
+    let mut client = Session::new(stream);
+    let server = async {
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        server
+    };
+    let write = async {
+        client.write_all(b"hello").await.unwrap();
+        client.flush().await.unwrap();
+    };
+    let mut server = tokio::join!(server, write).0;
+    assert_eq!(client.session_id(), Some(server.session_id()));
+    assert_eq!(listener.session_count(), 1);
+
+    proxy.set_up(false);
+    let mut buf = [0; 5];
+    // sessions expire while listener is polled
+    let accepting = tokio::spawn(async move {
+        loop {
+            let _ = listener.accept().await;
+        }
+    });
+    let err = tokio::time::timeout(TIMEOUT, server.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted);
+    assert!(!server.is_connected());
+
+    // server tells the session is unknown
+    proxy.set_up(true);
+    let err = tokio::time::timeout(TIMEOUT, client.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted);
+    // error stays
+    let err = client.write(b"x").await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted);
+    accepting.abort();
+}
+
+#[tokio::test]
+async fn unknown_session_gets_id_zero() {
+    let mut listener = SessionListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let _ = listener.accept().await;
+        }
+    });
+    let mut conn = TcpStream::connect(addr).await.unwrap();
+    let mut hello = b"RTS1".to_vec();
+    hello.extend_from_slice(&12345u64.to_be_bytes());
+    hello.extend_from_slice(&0u64.to_be_bytes());
+    conn.write_all(&hello).await.unwrap();
+    let mut reply = [0; 20];
+    tokio::time::timeout(TIMEOUT, conn.read_exact(&mut reply))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&reply[..4], b"RTS1");
+    assert_eq!(&reply[4..12], &0u64.to_be_bytes());
+}
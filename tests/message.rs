@@ -0,0 +1,112 @@
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::SinkExt;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio_retrying_tcpstream::{MessageSink, RetryingTcpStream};
+
+mod common;
+
+use common::{breaking_proxy, pattern};
+
+const MESSAGES: u32 = 1000;
+
+// Length, id and payload telling where message was cut
+fn message(id: u32) -> Bytes {
+    let len = 1000 + (id as usize * 7919) % 9000;
+    let mut message = BytesMut::with_capacity(8 + len);
+    message.put_u32(len as u32);
+    message.put_u32(id);
+    message.put_slice(&pattern(len, id as u8));
+    message.freeze()
+}
+
+// Ids of messages in data received on one connection. Every message has to be whole, except
+// the last one when connection broke.
+fn parse(mut data: &[u8]) -> Vec<u32> {
+    let mut ids = Vec::new();
+    while data.len() >= 8 {
+        let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        let id = u32::from_be_bytes(data[4..8].try_into().unwrap());
+        assert!(id < MESSAGES, "connection starts in the middle of message");
+        let expected = message(id);
+        assert_eq!(
+            8 + len,
+            expected.len(),
+            "header of message {} is corrupted",
+            id
+        );
+        if data.len() < expected.len() {
+            break;
+        }
+        assert!(
+            data[..expected.len()] == expected[..],
+            "message {} is corrupted",
+            id
+        );
+        ids.push(id);
+        data = &data[expected.len()..];
+    }
+    ids
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn messages_stay_whole_over_broken_connections() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (proxy, breaks) = breaking_proxy(listener.local_addr().unwrap(), 256 * 1024).await;
+    // data received on each connection, in order of accept
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let ended = Arc::new(AtomicUsize::new(0));
+    let (connections, done) = (received.clone(), ended.clone());
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let index = {
+                let mut connections = connections.lock().unwrap();
+                connections.push(Vec::new());
+                connections.len() - 1
+            };
+            let (connections, done) = (connections.clone(), done.clone());
+            tokio::spawn(async move {
+                let mut buf = [0; 16 * 1024];
+                while let Ok(n @ 1..) = conn.read(&mut buf).await {
+                    connections.lock().unwrap()[index].extend_from_slice(&buf[..n]);
+                }
+                done.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+    });
+
+    let stream = RetryingTcpStream::builder().target(proxy).build().unwrap();
+    let mut sink = MessageSink::new(stream);
+    tokio::time::timeout(Duration::from_secs(60), async {
+        for id in 0..MESSAGES {
+            sink.send(message(id)).await.unwrap();
+        }
+        sink.close().await.unwrap();
+        while ended.load(Ordering::Relaxed) < received.lock().unwrap().len() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let received = received.lock().unwrap();
+    let mut first_seen = Vec::new();
+    for data in received.iter() {
+        let ids = parse(data);
+        assert!(ids.windows(2).all(|w| w[0] < w[1]), "messages reordered");
+        for id in ids {
+            if first_seen.last().map_or(true, |&last| id > last) {
+                first_seen.push(id);
+            }
+        }
+    }
+    assert!(breaks.load(Ordering::Relaxed) >= 5);
+    // proxy acknowledges bytes it never forwards, sink then can't know they were lost
+    assert!(first_seen.len() as u32 > MESSAGES / 2);
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_retrying_tcpstream::policy::ConstantBackoff;
+use tokio_retrying_tcpstream::queue::{QueueOverflow, QueuePolicy};
+use tokio_retrying_tcpstream::RetryingTcpStream;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[tokio::test]
+async fn queued_request_reaches_listener_that_starts_later() {
+    // address nothing listens on yet
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut stream = RetryingTcpStream::builder()
+        .target(addr)
+        .reconnect_policy(ConstantBackoff::new(Duration::from_millis(20)))
+        .write_queue(QueuePolicy::new(1024, QueueOverflow::Block))
+        .build()
+        .unwrap();
+    stream.write_all(b"request").await.unwrap();
+    assert_eq!(stream.queued_bytes(), 7);
+
+    // client only waits for response, queued request goes out when it connects
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let server = async {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 7];
+        conn.read_exact(&mut buf).await.unwrap();
+        conn.write_all(b"response").await.unwrap();
+        buf
+    };
+    let client = async {
+        let mut response = [0; 8];
+        // connect started before listener was up fails, read reconnects
+        while stream.read_exact(&mut response).await.is_err() {}
+        response
+    };
+    let (response, request) = tokio::time::timeout(TIMEOUT, async { tokio::join!(client, server) })
+        .await
+        .unwrap();
+    assert_eq!(&request, b"request");
+    assert_eq!(&response, b"response");
+    assert_eq!(stream.queued_bytes(), 0);
+}
@@ -0,0 +1,69 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_retrying_tcpstream::resolve::{ResolveFuture, Resolver};
+use tokio_retrying_tcpstream::{EofPolicy, RetryingTcpStream, TcpTarget};
+
+// Returns next of given addresses on every call, remembers what was asked
+#[derive(Clone, Default)]
+struct Moving {
+    addrs: Arc<Mutex<VecDeque<SocketAddr>>>,
+    calls: Arc<Mutex<Vec<(String, u16)>>>,
+}
+
+impl Resolver for Moving {
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture {
+        self.calls.lock().unwrap().push((host.to_owned(), port));
+        let addr = self.addrs.lock().unwrap().pop_front();
+        Box::pin(async move { Ok(addr.into_iter().collect()) })
+    }
+}
+
+#[tokio::test]
+async fn host_is_resolved_on_every_reconnect() {
+    let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let resolver = Moving::default();
+    resolver
+        .addrs
+        .lock()
+        .unwrap()
+        .extend([first.local_addr().unwrap(), second.local_addr().unwrap()]);
+
+    let mut stream = RetryingTcpStream::builder()
+        .host("service.test:7000")
+        .resolver(resolver.clone())
+        .eof_policy(EofPolicy::Reconnect)
+        .build()
+        .unwrap();
+    assert_eq!(
+        stream.target(),
+        &TcpTarget::Host {
+            host: "service.test".to_owned(),
+            port: 7000
+        }
+    );
+
+    stream.write_all(b"x").await.unwrap();
+    // service moves, old instance closes connection
+    let (mut conn, _) = first.accept().await.unwrap();
+    conn.read_exact(&mut [0; 1]).await.unwrap();
+    drop(conn);
+    let read = async {
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        buf
+    };
+    let serve = async {
+        let (mut conn, _) = second.accept().await.unwrap();
+        conn.write_all(b"moved").await.unwrap();
+        conn
+    };
+    let (buf, _conn) = tokio::join!(read, serve);
+    assert_eq!(&buf, b"moved");
+    let expected = vec![("service.test".to_owned(), 7000); 2];
+    assert_eq!(*resolver.calls.lock().unwrap(), expected);
+}
@@ -0,0 +1,105 @@
+use std::future;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::task::Poll;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::sync::oneshot;
+use tokio_retrying_tcpstream::{RetryingTcpStream, Session, SessionListener};
+
+mod common;
+
+use common::{breaking_proxy, pattern};
+
+const LEN: usize = 2 * 1024 * 1024;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn survives_broken_connections() {
+    let mut listener = SessionListener::bind("127.0.0.1:0").await.unwrap();
+    let (proxy, breaks) = breaking_proxy(listener.local_addr().unwrap(), 256 * 1024).await;
+    let (client_done, mut client_done_rx) = oneshot::channel::<()>();
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        // keep accepting, reconnects are handled by listener
+        tokio::spawn(async move {
+            loop {
+                let _ = listener.accept().await;
+            }
+        });
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let write = async {
+            writer.write_all(&pattern(LEN, 2)).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let read = async {
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let received = tokio::join!(write, read).1;
+        // acknowledgement of client's end of data can be lost with the connection, then client
+        // reconnects and server answers while its stream is polled
+        let mut stream = reader.unsplit(writer);
+        let mut buf = [0; 1];
+        let serve = future::poll_fn(|cx| {
+            let _ = Pin::new(&mut stream).poll_read(cx, &mut ReadBuf::new(&mut buf));
+            Poll::<()>::Pending
+        });
+        tokio::select! {
+            _ = serve => {}
+            _ = &mut client_done_rx => {}
+        }
+        received
+    });
+
+    let stream = RetryingTcpStream::builder().target(proxy).build().unwrap();
+    let mut session = Session::new(stream);
+    let client = async {
+        let mut received = Vec::new();
+        session.read_to_end(&mut received).await.unwrap();
+        // small writes, so that connections break between them too
+        for chunk in pattern(LEN, 1).chunks(10_000) {
+            session.write_all(chunk).await.unwrap();
+        }
+        session.shutdown().await.unwrap();
+        client_done.send(()).unwrap();
+        received
+    };
+    let (from_server, from_client) = tokio::time::timeout(Duration::from_secs(60), async {
+        tokio::join!(client, server)
+    })
+    .await
+    .unwrap();
+
+    assert!(from_client.unwrap() == pattern(LEN, 1));
+    assert!(from_server == pattern(LEN, 2));
+    assert!(breaks.load(Ordering::Relaxed) >= 10);
+}
+
+#[tokio::test]
+async fn failing_connect_yields() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    // documentation address, never local, so bind fails right away
+    let stream = RetryingTcpStream::builder()
+        .target(listener.local_addr().unwrap())
+        .bind_addr("192.0.2.1:0".parse().unwrap())
+        .build()
+        .unwrap();
+    let mut session = Session::new(stream);
+    // runtime has single thread, timeout fires only when session returns Pending
+    let write = async {
+        session.write_all(b"hello").await?;
+        session.flush().await
+    };
+    assert!(tokio::time::timeout(Duration::from_millis(200), write)
+        .await
+        .is_err());
+    let mut buf = [0; 8];
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), session.read(&mut buf))
+            .await
+            .is_err()
+    );
+}
@@ -0,0 +1,59 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_retrying_tcpstream::{EofPolicy, RetryingTcpStream, TcpStreamSettings};
+
+#[tokio::test]
+async fn setters_survive_reconnect() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut stream = RetryingTcpStream::builder()
+        .target(listener.local_addr().unwrap())
+        .eof_policy(EofPolicy::Reconnect)
+        .build()
+        .unwrap();
+    // recorded before there is connection
+    stream.set_nodelay(true).unwrap();
+    stream.write_all(b"x").await.unwrap();
+    let (mut conn, _) = listener.accept().await.unwrap();
+    assert!(stream.nodelay().unwrap());
+
+    stream.set_keepalive(Some(Duration::from_secs(60))).unwrap();
+    stream
+        .set_tcp_settings(TcpStreamSettings {
+            linger: Some(Duration::from_secs(1)),
+            ..stream.tcp_settings().clone()
+        })
+        .unwrap();
+    let live = stream.live_tcp_settings().unwrap();
+    assert_eq!(live.keepalive, Some(Duration::from_secs(60)));
+    assert_eq!(live.linger, Some(Duration::from_secs(1)));
+    stream
+        .set_tcp_settings(TcpStreamSettings {
+            linger: None,
+            ..stream.tcp_settings().clone()
+        })
+        .unwrap();
+
+    // peer closes, stream reconnects on read
+    conn.read_exact(&mut [0; 1]).await.unwrap();
+    drop(conn);
+    let read = async {
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+    };
+    let serve = async {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        conn.write_all(b"again").await.unwrap();
+        conn
+    };
+    let _conn = tokio::join!(read, serve).1;
+
+    assert_eq!(stream.stats().connects, 2);
+    assert!(stream.nodelay().unwrap());
+    assert_eq!(stream.keepalive().unwrap(), Some(Duration::from_secs(60)));
+    let live = stream.live_tcp_settings().unwrap();
+    assert!(live.nodelay);
+    assert_eq!(live.keepalive, Some(Duration::from_secs(60)));
+    assert_eq!(live.linger, None);
+}
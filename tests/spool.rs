@@ -0,0 +1,129 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_retrying_tcpstream::policy::ConstantBackoff;
+use tokio_retrying_tcpstream::queue::{QueueOverflow, QueuePolicy};
+use tokio_retrying_tcpstream::spool::{Spool, SyncPolicy};
+use tokio_retrying_tcpstream::{EofPolicy, ErrorCategory, RetryingError, RetryingTcpStream};
+
+mod common;
+
+use common::pattern;
+
+const LEN: usize = 300 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn spooled(target: SocketAddr, path: &Path, policy: QueuePolicy) -> RetryingTcpStream {
+    RetryingTcpStream::builder()
+        .target(target)
+        .reconnect_policy(ConstantBackoff::new(Duration::from_millis(20)))
+        .eof_policy(EofPolicy::Reconnect)
+        .spool(Spool::open(path, policy, SyncPolicy::Never).unwrap())
+        .build()
+        .unwrap()
+}
+
+// Address nothing listens on
+async fn closed_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+#[tokio::test]
+async fn drains_in_order_after_reconnect() {
+    let dir = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let policy = QueuePolicy::new(1024 * 1024, QueueOverflow::Block);
+    let mut stream = spooled(addr, &dir.path().join("spool"), policy);
+
+    let write = async {
+        stream.write_all(b"first").await.unwrap();
+        stream.flush().await.unwrap();
+    };
+    let read = async {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 5];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"first");
+    };
+    tokio::join!(write, read);
+    // server goes away, stream notices on read and reconnects
+    drop(listener);
+    let mut buf = [0; 1];
+    assert!(stream.read(&mut buf).await.is_err());
+
+    let data = pattern(LEN, 1);
+    stream.write_all(&data).await.unwrap();
+    assert_eq!(stream.queued_bytes(), LEN);
+
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let flush = async {
+        stream.flush().await.unwrap();
+        stream.write_all(b"last").await.unwrap();
+        stream.flush().await.unwrap();
+    };
+    let read = async {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut received = vec![0; LEN + 4];
+        conn.read_exact(&mut received).await.unwrap();
+        received
+    };
+    let received = tokio::time::timeout(TIMEOUT, async { tokio::join!(flush, read).1 })
+        .await
+        .unwrap();
+    assert!(received[..LEN] == data[..]);
+    assert_eq!(&received[LEN..], b"last");
+    assert_eq!(stream.queued_bytes(), 0);
+}
+
+#[tokio::test]
+async fn drains_after_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("spool");
+    let policy = QueuePolicy::new(1024 * 1024, QueueOverflow::Block);
+    let data = pattern(LEN, 2);
+
+    let mut stream = spooled(closed_addr().await, &path, policy);
+    stream.write_all(&data).await.unwrap();
+    assert_eq!(stream.queued_bytes(), LEN);
+    drop(stream);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut stream = spooled(listener.local_addr().unwrap(), &path, policy);
+    assert_eq!(stream.queued_bytes(), LEN);
+    let write = async {
+        stream.write_all(b"new").await.unwrap();
+        stream.flush().await.unwrap();
+    };
+    let read = async {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut received = vec![0; LEN + 3];
+        conn.read_exact(&mut received).await.unwrap();
+        received
+    };
+    let received = tokio::time::timeout(TIMEOUT, async { tokio::join!(write, read).1 })
+        .await
+        .unwrap();
+    assert!(received[..LEN] == data[..]);
+    assert_eq!(&received[LEN..], b"new");
+    drop(stream);
+    assert!(Spool::open(&path, policy, SyncPolicy::Never)
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn full_spool_fails_write() {
+    let dir = tempfile::tempdir().unwrap();
+    let policy = QueuePolicy::new(8, QueueOverflow::Error);
+    let mut stream = spooled(closed_addr().await, &dir.path().join("spool"), policy);
+    assert_eq!(stream.write(b"0123456789").await.unwrap(), 8);
+    let err = stream.write(b"89").await.unwrap_err();
+    let category = RetryingError::from_io(&err).map(RetryingError::category);
+    assert_eq!(category, Some(ErrorCategory::QueueFull));
+    assert_eq!(stream.queued_bytes(), 8);
+}